@@ -17,8 +17,8 @@ fn run(config: Config) -> Result<()> {
             config.db_path
         );
     }
-    let store = DBStore::open(&config.db_path);
-    store.compact();
+    let store = DBStore::open(&config.db_path)?;
+    store.compact()?;
     Ok(())
 }
 
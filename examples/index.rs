@@ -20,6 +20,7 @@ fn run() -> Result<()> {
     let daemon = Daemon::new(
         &config.daemon_dir,
         config.daemon_rpc_addr,
+        config.p2p_blocks_addr(),
         config.cookie_getter(),
         config.network_type,
         signal.clone(),
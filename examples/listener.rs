@@ -4,12 +4,15 @@ extern crate electrs;
 extern crate log;
 
 use electrs::config::Config;
-use electrs::notify;
+use electrs::notify::{Event, Notifier};
 
 fn main() {
-    let _ = Config::from_args();
-    let rx = notify::run().into_receiver();
-    for blockhash in rx.iter() {
-        info!("block {}", blockhash.be_hex_string())
+    let config = Config::from_args();
+    let notifier = Notifier::start(config.network_type, config.daemon_p2p_addr);
+    for event in notifier.subscribe().iter() {
+        match event {
+            Event::Block(blockhash) => info!("block {}", blockhash.be_hex_string()),
+            Event::Transaction(txid) => info!("tx {}", txid.be_hex_string()),
+        }
     }
 }
@@ -27,12 +27,13 @@ fn run(config: Config) -> Result<()> {
     let daemon = Daemon::new(
         &config.daemon_dir,
         config.daemon_rpc_addr,
+        config.p2p_blocks_addr(),
         config.cookie_getter(),
         config.network_type,
         signal,
         &metrics,
     )?;
-    let store = DBStore::open(&config.db_path);
+    let store = DBStore::open(&config.db_path)?;
     bulk::index(&daemon, &metrics, store)?;
     Ok(())
 }
@@ -1,12 +1,48 @@
 use bitcoin::util::hash::Sha256dHash;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
+use util::{HeaderEntry, HeaderList};
 use {daemon, index, signal::Waiter, store};
 
 use errors::*;
 
+/// The indexed headers, together with a DB snapshot taken right after their blocks were indexed.
+pub struct Snapshot {
+    store: store::SnapshotStore,
+    headers: Arc<HeaderList>,
+}
+
+impl Snapshot {
+    pub fn store(&self) -> &store::SnapshotStore {
+        &self.store
+    }
+
+    pub fn best_header(&self) -> Option<HeaderEntry> {
+        self.headers
+            .header_by_blockhash(self.headers.tip())
+            .cloned()
+    }
+
+    pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
+        self.headers.header_by_height(height).cloned()
+    }
+
+    pub fn get_header_by_hash(&self, blockhash: &Sha256dHash) -> Option<HeaderEntry> {
+        self.headers.header_by_blockhash(blockhash).cloned()
+    }
+
+    pub fn get_header_proof(
+        &self,
+        height: usize,
+        cp_height: usize,
+    ) -> Option<(Vec<Sha256dHash>, Sha256dHash)> {
+        self.headers.header_proof(height, cp_height)
+    }
+}
+
 pub struct App {
     store: store::DBStore,
+    snapshot: RwLock<Arc<Snapshot>>, // queries run on the latest indexed snapshot
     index: index::Index,
     daemon: daemon::Daemon,
     tip: Mutex<Sha256dHash>,
@@ -19,8 +55,13 @@ impl App {
         daemon: daemon::Daemon,
     ) -> Result<Arc<App>> {
         index.reload(&store);
+        let snapshot = RwLock::new(Arc::new(Snapshot {
+            store: store.snapshot(),
+            headers: index.headers(),
+        }));
         Ok(Arc::new(App {
             store,
+            snapshot,
             index,
             daemon: daemon.reconnect()?,
             tip: Mutex::new(Sha256dHash::default()),
@@ -30,8 +71,9 @@ impl App {
     pub fn write_store(&self) -> &store::WriteStore {
         &self.store
     }
-    pub fn read_store(&self) -> &store::ReadStore {
-        &self.store
+    /// Headers and rows should be read from the same snapshot, so they are consistent.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
     }
     pub fn index(&self) -> &index::Index {
         &self.index
@@ -40,12 +82,18 @@ impl App {
         &self.daemon
     }
 
-    pub fn update(&self, signal: &Waiter) -> Result<bool> {
+    /// Returns the script hashes touched by new blocks (after they can be queried).
+    pub fn update(&self, signal: &Waiter) -> Result<index::Touched> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
-        let new_block = *tip != self.daemon().getbestblockhash()?;
-        if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+        if *tip == self.daemon().getbestblockhash()? {
+            return Ok(index::Touched::default());
         }
-        Ok(new_block)
+        let (new_tip, touched) = self.index().update(self.write_store(), &signal)?;
+        *tip = new_tip;
+        *self.snapshot.write().unwrap() = Arc::new(Snapshot {
+            store: self.store.snapshot(),
+            headers: self.index().headers(),
+        });
+        Ok(touched)
     }
 }
@@ -0,0 +1,77 @@
+//! Offline consistency checker for the index DB (electrs must not be running).
+extern crate electrs;
+extern crate serde_json;
+extern crate stderrlog;
+
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate log;
+
+use clap::{App, Arg};
+use error_chain::ChainedError;
+use std::path::Path;
+use std::process;
+
+use electrs::{check, errors::*, store::DBStore};
+
+fn run() -> Result<bool> {
+    let m = App::new("Electrum Rust Server index checker")
+        .version(crate_version!())
+        .arg(
+            Arg::with_name("verbosity")
+                .short("v")
+                .multiple(true)
+                .help("Increase logging verbosity"),
+        )
+        .arg(
+            Arg::with_name("db_dir")
+                .long("db-dir")
+                .help("Directory of the index database (default: ./db/)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .help("Select Bitcoin network type ('mainnet', 'testnet' or 'regtest')")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("Delete dangling rows from the index"),
+        )
+        .get_matches();
+
+    let mut log = stderrlog::new();
+    log.verbosity(m.occurrences_of("verbosity") as usize);
+    log.init().expect("logging initialization failed");
+
+    let network_name = m.value_of("network").unwrap_or("mainnet");
+    let db_path = Path::new(m.value_of("db_dir").unwrap_or("./db")).join(network_name);
+    if !db_path.exists() {
+        bail!("DB {:?} does not exist", db_path);
+    }
+    let repair = m.is_present("repair");
+    // the DB is written only when repairing it (without upgrading its schema)
+    let mut store = DBStore::open_unchecked(&db_path);
+    if repair {
+        store = store.enable_compaction()?; // deletions must be written to the WAL
+    }
+    let report = check::check(&store, repair);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    Ok(report.is_consistent() || (repair && report.errors.is_empty()))
+}
+
+fn main() {
+    match run() {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            error!("check failed: {}", e.display_chain());
+            process::exit(2);
+        }
+    }
+}
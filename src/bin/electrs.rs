@@ -10,8 +10,17 @@ use std::time::Duration;
 
 use electrs::{
     app::App, bulk, config::Config, daemon::Daemon, errors::*, index::Index, metrics::Metrics,
-    query::Query, rpc::RPC, signal::Waiter, store::DBStore,
+    migrate, query::Query, rpc::RPC, signal::Waiter, store::DBStore,
 };
+use electrs::{
+    notify::{self, Notifier},
+    rest,
+    rpc::{Limits, Transport},
+    tls::TlsAcceptor,
+};
+use std::sync::Arc;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5); // when no new inventory is announced
 
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::new();
@@ -21,13 +30,15 @@ fn run_server(config: &Config) -> Result<()> {
     let daemon = Daemon::new(
         &config.daemon_dir,
         config.daemon_rpc_addr,
+        config.p2p_blocks_addr(),
         config.cookie_getter(),
         config.network_type,
         signal.clone(),
         &metrics,
     )?;
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path);
+    let store = migrate::upgrade(DBStore::open_unchecked(&config.db_path), &daemon)
+        .chain_err(|| format!("failed to open DB at {:?}", config.db_path))?;
     let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
     let store = if config.skip_bulk_import {
         index.update(&store, &signal)?; // slower: uses JSONRPC for fetching blocks
@@ -37,16 +48,48 @@ fn run_server(config: &Config) -> Result<()> {
     }?;
 
     let app = App::new(store, index, daemon)?;
-    let query = Query::new(app.clone(), &metrics);
+    let query = Query::new(
+        app.clone(),
+        &metrics,
+        config.tx_cache_size,
+        config.status_cache_size,
+        config.max_history_size,
+    );
+    if let Some(http_addr) = config.http_addr {
+        rest::start(http_addr, query.clone());
+    }
 
+    let mut listeners = vec![(config.electrum_rpc_addr, Transport::Tcp)];
+    if let (&Some(ref cert_path), &Some(ref key_path)) = (&config.ssl_cert, &config.ssl_key) {
+        let tls = TlsAcceptor::new(cert_path, key_path)?;
+        listeners.push((config.electrum_ssl_addr, Transport::Tls(Arc::new(tls))));
+    }
+    if let Some(ws_addr) = config.ws_addr {
+        listeners.push((ws_addr, Transport::WebSocket));
+    }
+    let limits = Limits {
+        max_connections_per_ip: config.max_connections_per_ip,
+        max_subscriptions: config.max_subscriptions,
+        max_request_size: config.max_request_size,
+        max_request_rate: config.max_request_rate,
+    };
+    let events = if config.daemon_p2p_notify {
+        Some(Notifier::start(config.network_type, config.daemon_p2p_addr).subscribe())
+    } else {
+        None
+    };
     let mut server = None; // Electrum RPC server
     loop {
-        app.update(&signal)?;
+        query.invalidate_statuses(&app.update(&signal)?);
         query.update_mempool()?;
         server
-            .get_or_insert_with(|| RPC::start(config.electrum_rpc_addr, query.clone(), &metrics))
+            .get_or_insert_with(|| RPC::start(listeners.clone(), limits, query.clone(), &metrics))
             .notify(); // update subscribed clients
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+        let result = match events {
+            Some(ref events) => notify::wait(events, &signal, POLL_INTERVAL),
+            None => signal.wait(POLL_INTERVAL),
+        };
+        if let Err(err) = result {
             info!("stopping server: {}", err);
             break;
         }
@@ -120,7 +120,7 @@ impl Parser {
     }
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
+pub fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
     let mut cursor = Cursor::new(&blob);
     let mut blocks = vec![];
     let max_pos = blob.len() as u64;
@@ -258,12 +258,12 @@ pub fn index(daemon: &Daemon, metrics: &Metrics, store: DBStore) -> Result<DBSto
         Ok(store)
     };
     // Enable auto compactions after bulk indexing is over.
-    result.map(|store| store.enable_compaction())
+    result.and_then(|store| store.enable_compaction())
 }
 
 pub fn full_compaction(store: DBStore) -> Result<DBStore> {
     store.flush();
-    let store = store.compact().enable_compaction();
+    let store = store.compact()?.enable_compaction()?;
     store.write(vec![finish_marker_row()]);
     Ok(store)
 }
@@ -0,0 +1,331 @@
+use bincode;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::serialize::{deserialize, BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use index::{BlockKey, BlockTxidsRow, TxInRow, TxOutRow, TxRow, UndoRow};
+use migrate;
+use store::{Row, WriteStore};
+use util::{Bytes, FullHash, HashPrefix};
+
+const DELETE_BATCH_SIZE: usize = 100_000;
+
+#[derive(Serialize, Default)]
+pub struct RowStats {
+    pub scanned: u64,
+    pub dangling: u64,
+}
+
+/// Machine-readable summary of an index consistency check.
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub version: Option<u32>,
+    pub tip: Option<String>,
+    pub height: Option<usize>,
+    pub finished: bool,         // full compaction marker
+    pub unverified_blocks: u64, // without `M` rows, so their `T` rows are checked only by height
+    pub rows: BTreeMap<String, RowStats>,
+    pub errors: Vec<String>,
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.errors.is_empty() && self.rows.values().all(|stats| stats.dangling == 0)
+    }
+}
+
+struct Checker<'a> {
+    store: &'a WriteStore,
+    repair: bool,
+    pending: Vec<Bytes>,            // dangling keys to be deleted
+    dangling_txids: HashSet<Bytes>, // `T` keys of transactions missing from their block
+    report: Report,
+}
+
+impl<'a> Checker<'a> {
+    fn stats(&mut self, code: u8) -> &mut RowStats {
+        self.report
+            .rows
+            .entry((code as char).to_string())
+            .or_insert_with(RowStats::default)
+    }
+
+    fn visit(&mut self, row: &Row, dangling: bool) {
+        self.stats(row.key[0]).scanned += 1;
+        if dangling {
+            self.dangling(row);
+        }
+    }
+
+    fn dangling(&mut self, row: &Row) {
+        self.stats(row.key[0]).dangling += 1;
+        trace!("dangling row {:?}", row.key);
+        if self.repair {
+            self.pending.push(row.key.clone());
+            if self.pending.len() >= DELETE_BATCH_SIZE {
+                self.store.delete(self.pending.split_off(0));
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.repair {
+            self.store.delete(self.pending.split_off(0));
+            self.store.flush();
+        }
+    }
+
+    // Walks back the `B` headers from the `L` marker, like `read_indexed_headers()`.
+    fn read_chain(&mut self) -> Option<Vec<Sha256dHash>> {
+        let mut headers = HashMap::<Sha256dHash, BlockHeader>::new();
+        for row in self.store.iter_scan(b"B") {
+            let key: BlockKey = bincode::deserialize(&row.key).expect("failed to parse BlockKey");
+            let header: BlockHeader = deserialize(&row.value).expect("failed to parse header");
+            headers.insert(deserialize(&key.hash).unwrap(), header);
+        }
+        let null_hash = Sha256dHash::default();
+        let mut blockhash = match self.store.get(b"L") {
+            Some(value) => deserialize(&value).expect("failed to parse last indexed block"),
+            None => null_hash,
+        };
+        let mut chain = vec![];
+        while blockhash != null_hash {
+            let header = match headers.get(&blockhash) {
+                Some(header) => header,
+                None => {
+                    let msg = format!("missing {} header (at {} from tip)", blockhash, chain.len());
+                    self.report.errors.push(msg);
+                    return None;
+                }
+            };
+            chain.push(blockhash);
+            blockhash = header.prev_blockhash;
+        }
+        chain.reverse();
+        if let Some(tip) = chain.last() {
+            self.report.tip = Some(tip.be_hex_string());
+            self.report.height = Some(chain.len() - 1);
+        }
+        Some(chain)
+    }
+
+    fn check_blocks(&mut self, heights: &HashMap<Sha256dHash, usize>) {
+        for row in self.store.iter_scan(b"B") {
+            let key: BlockKey = bincode::deserialize(&row.key).unwrap();
+            let header: BlockHeader = deserialize(&row.value).unwrap();
+            let blockhash: Sha256dHash = deserialize(&key.hash).unwrap();
+            if header.bitcoin_hash() != blockhash {
+                self.report
+                    .errors
+                    .push(format!("{} header has wrong hash", blockhash));
+            }
+            self.visit(&row, !heights.contains_key(&blockhash)); // stale block
+        }
+        for row in self.store.iter_scan(b"M") {
+            let key = BlockTxidsRow::from_row(&row).key;
+            let blockhash: Sha256dHash = deserialize(&key.hash).unwrap();
+            self.visit(&row, !heights.contains_key(&blockhash)); // stale block
+        }
+        for row in self.store.iter_scan(b"U") {
+            let blockhash: Sha256dHash = deserialize(&UndoRow::from_row(&row).key.hash).unwrap();
+            self.visit(&row, !heights.contains_key(&blockhash)); // interrupted rollback
+        }
+    }
+
+    fn block_txids(&self, blockhash: &Sha256dHash) -> Option<Vec<FullHash>> {
+        let key = BlockTxidsRow::filter(blockhash);
+        let value = self.store.get(&key)?;
+        Some(BlockTxidsRow::from_row(&Row { key, value }).txids)
+    }
+
+    // Each `T` row must belong to the block at its height (according to the block's `M` row).
+    fn check_txids(&mut self, chain: &[Sha256dHash]) {
+        let mut counts = vec![0usize; chain.len()]; // of `T` rows at each height
+        for row in self.store.iter_scan(b"T") {
+            let height = TxRow::from_row(&row).height as usize;
+            self.visit(&row, height >= chain.len());
+            if let Some(count) = counts.get_mut(height) {
+                *count += 1;
+            }
+        }
+        // If the block's transactions are all indexed at its height, and there are no other
+        // `T` rows at this height, they must all belong to the block.
+        let mut suspects = HashMap::<usize, HashSet<FullHash>>::new();
+        for (height, blockhash) in chain.iter().enumerate() {
+            let txids = match self.block_txids(blockhash) {
+                Some(txids) => txids,
+                None => {
+                    self.report.unverified_blocks += 1;
+                    continue;
+                }
+            };
+            let indexed = txids
+                .iter()
+                .filter_map(|txid| {
+                    self.store
+                        .get(&TxRow::filter_full(&deserialize(txid).unwrap()))
+                })
+                .filter(|value| bincode::deserialize::<u32>(value).unwrap() as usize == height)
+                .count();
+            if indexed < counts[height] {
+                suspects.insert(height, txids.into_iter().collect());
+            }
+        }
+        if suspects.is_empty() {
+            return;
+        }
+        for row in self.store.iter_scan(b"T") {
+            let txrow = TxRow::from_row(&row);
+            if let Some(txids) = suspects.get(&(txrow.height as usize)) {
+                if !txids.contains(&txrow.key.txid) {
+                    self.dangling(&row);
+                    self.dangling_txids.insert(row.key);
+                }
+            }
+        }
+    }
+
+    fn is_indexed(&self, txid_prefix: &HashPrefix, chain_len: usize) -> bool {
+        self.store
+            .iter_scan(&TxRow::filter_prefix(txid_prefix))
+            .any(|row| {
+                (TxRow::from_row(&row).height as usize) < chain_len
+                    && !self.dangling_txids.contains(&row.key)
+            })
+    }
+
+    fn check_prefixes(&mut self, chain_len: usize) {
+        for row in self.store.iter_scan(b"O") {
+            let txid_prefix = TxOutRow::from_row(&row).txid_prefix;
+            let dangling = !self.is_indexed(&txid_prefix, chain_len);
+            self.visit(&row, dangling);
+        }
+        for row in self.store.iter_scan(b"I") {
+            let txid_prefix = TxInRow::from_row(&row).txid_prefix;
+            let dangling = !self.is_indexed(&txid_prefix, chain_len);
+            self.visit(&row, dangling);
+        }
+    }
+}
+
+/// Verifies that all index rows belong to the indexed chain, optionally deleting the ones that don't.
+pub fn check(store: &WriteStore, repair: bool) -> Report {
+    let mut checker = Checker {
+        store,
+        repair,
+        pending: vec![],
+        dangling_txids: HashSet::new(),
+        report: Report::default(),
+    };
+    match migrate::read_version(store) {
+        Ok(version) => checker.report.version = version,
+        Err(e) => checker.report.errors.push(e.to_string()),
+    }
+    if let Some(version) = checker.report.version {
+        if version != migrate::DB_VERSION {
+            let msg = format!(
+                "unsupported schema version {} (expected {})",
+                version,
+                migrate::DB_VERSION
+            );
+            checker.report.errors.push(msg);
+            return checker.report; // rows can't be parsed
+        }
+    }
+    checker.report.finished = store.get(b"F").is_some();
+    if checker.report.finished && store.get(b"L").is_none() {
+        let msg = "full compaction marker found without last indexed block".to_owned();
+        checker.report.errors.push(msg);
+    }
+    let chain = match checker.read_chain() {
+        Some(chain) => chain,
+        None => return checker.report, // dangling rows can't be found without a valid chain
+    };
+    let heights: HashMap<Sha256dHash, usize> = chain
+        .iter()
+        .enumerate()
+        .map(|(height, hash)| (*hash, height))
+        .collect();
+    info!("checking blocks");
+    checker.check_blocks(&heights);
+    info!("checking transactions");
+    checker.check_txids(&chain);
+    info!("checking inputs and outputs");
+    checker.check_prefixes(chain.len());
+    checker.flush();
+    checker.report.repaired = repair;
+    checker.report
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::network::serialize::BitcoinHash;
+    use bitcoin::util::hash::Sha256dHash;
+
+    use super::*;
+    use fake::MemStore;
+    use index::{index_block_with_undo, index_transaction};
+
+    fn block(prev_blockhash: Sha256dHash, height: u32) -> Block {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: height,
+            input: vec![TxIn {
+                prev_hash: Sha256dHash::default(),
+                prev_index: 0xffff_ffff,
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::new(),
+            }],
+        };
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce: height,
+            },
+            txdata: vec![coinbase],
+        }
+    }
+
+    #[test]
+    fn test_txid_not_in_block() {
+        let store = MemStore::default();
+        store.write(vec![migrate::version_row(migrate::DB_VERSION)]);
+        let genesis = block(Sha256dHash::default(), 0);
+        let block1 = block(genesis.bitcoin_hash(), 1);
+        store.write(index_block_with_undo(&genesis, 0));
+        store.write(index_block_with_undo(&block1, 1));
+        assert!(check(&store, false).is_consistent());
+
+        // a stale transaction, left at the height of an indexed block
+        let mut stale = block(genesis.bitcoin_hash(), 2).txdata.remove(0);
+        stale.output[0].script_pubkey = Script::from(vec![0x51]);
+        let mut rows = vec![];
+        index_transaction(&stale, 1, &mut rows);
+        store.write(rows);
+
+        let report = check(&store, false);
+        assert_eq!(report.version, Some(migrate::DB_VERSION));
+        assert_eq!(report.height, Some(1));
+        assert_eq!(report.unverified_blocks, 0);
+        assert_eq!(report.rows["T"].dangling, 1);
+        assert_eq!(report.rows["O"].dangling, 1);
+        assert!(!report.is_consistent());
+
+        assert!(check(&store, true).errors.is_empty());
+        assert!(check(&store, false).is_consistent());
+    }
+}
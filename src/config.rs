@@ -17,11 +17,26 @@ pub struct Config {
     pub db_path: PathBuf,              // RocksDB directory path
     pub daemon_dir: PathBuf,           // Bitcoind data directory
     pub daemon_rpc_addr: SocketAddr,   // for connecting Bitcoind JSONRPC
+    pub daemon_p2p_addr: SocketAddr,   // for receiving Bitcoind P2P announcements
+    pub daemon_p2p_notify: bool,       // wake up on P2P announcements (instead of only polling)
+    pub daemon_p2p_blocks: bool,       // fetch blocks over P2P (instead of JSONRPC)
     pub cookie: Option<String>,        // for bitcoind JSONRPC authentication ("USER:PASSWORD")
     pub electrum_rpc_addr: SocketAddr, // for serving Electrum clients
+    pub electrum_ssl_addr: SocketAddr, // for serving Electrum clients over SSL
+    pub ssl_cert: Option<PathBuf>,     // PEM certificate chain (SSL is disabled if not set)
+    pub ssl_key: Option<PathBuf>,      // PEM private key
+    pub ws_addr: Option<SocketAddr>,   // for serving Electrum clients over WebSocket
+    pub http_addr: Option<SocketAddr>, // for serving the HTTP REST API
     pub monitoring_addr: SocketAddr,   // for Prometheus monitoring
     pub skip_bulk_import: bool,        // slower initial indexing, for low-memory systems
     pub index_batch_size: usize,       // number of blocks to index in parallel
+    pub tx_cache_size: usize,          // in bytes
+    pub status_cache_size: usize,      // number of script hashes
+    pub max_connections_per_ip: usize, // Electrum RPC resource limits (0 = unlimited)
+    pub max_subscriptions: usize,
+    pub max_request_size: usize,
+    pub max_request_rate: usize,
+    pub max_history_size: usize,
 }
 
 impl Config {
@@ -69,12 +84,60 @@ impl Config {
                     .help("Electrum server JSONRPC 'addr:port' to listen on (default: '127.0.0.1:50001' for mainnet, '127.0.0.1:60001' for testnet and '127.0.0.1:60401' for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("electrum_ssl_addr")
+                    .long("electrum-ssl-addr")
+                    .help("Electrum server SSL 'addr:port' to listen on (default: '127.0.0.1:50002' for mainnet, '127.0.0.1:60002' for testnet and '127.0.0.1:60402' for regtest)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("ssl_cert")
+                    .long("ssl-cert")
+                    .help("PEM file with the SSL certificate chain (enables the SSL port)")
+                    .takes_value(true)
+                    .requires("ssl_key"),
+            )
+            .arg(
+                Arg::with_name("ssl_key")
+                    .long("ssl-key")
+                    .help("PEM file with the SSL private key")
+                    .takes_value(true)
+                    .requires("ssl_cert"),
+            )
+            .arg(
+                Arg::with_name("electrum_ws_addr")
+                    .long("electrum-ws-addr")
+                    .help("Electrum server WebSocket 'addr:port' to listen on (default: disabled)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("http_addr")
+                    .long("http-addr")
+                    .help("HTTP REST API 'addr:port' to listen on (default: disabled)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("daemon_rpc_addr")
                     .long("daemon-rpc-addr")
                     .help("Bitcoin daemon JSONRPC 'addr:port' to connect (default: 127.0.0.1:8332 for mainnet, 127.0.0.1:18332 for testnet and 127.0.0.1:18443 for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("daemon_p2p_addr")
+                    .long("daemon-p2p-addr")
+                    .help("Bitcoin daemon P2P 'addr:port' to connect (default: 127.0.0.1:8333 for mainnet, 127.0.0.1:18333 for testnet and 127.0.0.1:18444 for regtest)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("daemon_p2p_notify")
+                    .long("daemon-p2p-notify")
+                    .help("Update the index and mempool as soon as new blocks and transactions are announced over Bitcoin daemon P2P connection (instead of only polling every 5 seconds)"),
+            )
+            .arg(
+                Arg::with_name("daemon_p2p_blocks")
+                    .long("daemon-p2p-blocks")
+                    .help("Fetch new blocks over Bitcoin daemon P2P connection (falling back to JSONRPC on failure)"),
+            )
             .arg(
                 Arg::with_name("monitoring_addr")
                     .long("monitoring-addr")
@@ -92,6 +155,48 @@ impl Config {
                     .help("Number of blocks to get in one JSONRPC request from bitcoind")
                     .default_value("100"),
             )
+            .arg(
+                Arg::with_name("tx_cache_size_mb")
+                    .long("tx-cache-size-mb")
+                    .help("Total size of transactions to cache in memory (in MB)")
+                    .default_value("10"),
+            )
+            .arg(
+                Arg::with_name("status_cache_size")
+                    .long("status-cache-size")
+                    .help("Number of script hash statuses to cache in memory (shared by all Electrum connections)")
+                    .default_value("10000"),
+            )
+            .arg(
+                Arg::with_name("max_connections_per_ip")
+                    .long("max-connections-per-ip")
+                    .help("Maximum number of concurrent Electrum connections from a single IP address (0 = unlimited)")
+                    .default_value("100"),
+            )
+            .arg(
+                Arg::with_name("max_subscriptions")
+                    .long("max-subscriptions")
+                    .help("Maximum number of script hash subscriptions per Electrum connection (0 = unlimited)")
+                    .default_value("10000"),
+            )
+            .arg(
+                Arg::with_name("max_request_size")
+                    .long("max-request-size")
+                    .help("Maximum size of an Electrum request (in bytes)")
+                    .default_value("1000000"),
+            )
+            .arg(
+                Arg::with_name("max_request_rate")
+                    .long("max-request-rate")
+                    .help("Maximum average rate of Electrum requests per connection, allowing bursts of up to 10 seconds' worth (in requests per second, 0 = unlimited)")
+                    .default_value("100"),
+            )
+            .arg(
+                Arg::with_name("max_history_size")
+                    .long("max-history-size")
+                    .help("Maximum number of transactions to look up for a single script hash, to prevent very popular addresses from stalling the server (0 = unlimited)")
+                    .default_value("0"),
+            )
             .get_matches();
 
         let network_name = m.value_of("network").unwrap_or("mainnet");
@@ -109,11 +214,21 @@ impl Config {
             Network::Testnet => 18332,
             Network::Regtest => 18443,
         };
+        let default_daemon_p2p_port = match network_type {
+            Network::Mainnet => 8333,
+            Network::Testnet => 18333,
+            Network::Regtest => 18444,
+        };
         let default_electrum_port = match network_type {
             Network::Mainnet => 50001,
             Network::Testnet => 60001,
             Network::Regtest => 60401,
         };
+        let default_electrum_ssl_port = match network_type {
+            Network::Mainnet => 50002,
+            Network::Testnet => 60002,
+            Network::Regtest => 60402,
+        };
         let default_monitoring_port = match network_type {
             Network::Mainnet => 4224,
             Network::Testnet => 14224,
@@ -125,11 +240,27 @@ impl Config {
             .unwrap_or(&format!("127.0.0.1:{}", default_daemon_port))
             .parse()
             .expect("invalid Bitcoind RPC address");
+        let daemon_p2p_addr: SocketAddr = m
+            .value_of("daemon_p2p_addr")
+            .unwrap_or(&format!("127.0.0.1:{}", default_daemon_p2p_port))
+            .parse()
+            .expect("invalid Bitcoind P2P address");
         let electrum_rpc_addr: SocketAddr = m
             .value_of("electrum_rpc_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port))
             .parse()
             .expect("invalid Electrum RPC address");
+        let electrum_ssl_addr: SocketAddr = m
+            .value_of("electrum_ssl_addr")
+            .unwrap_or(&format!("127.0.0.1:{}", default_electrum_ssl_port))
+            .parse()
+            .expect("invalid Electrum SSL address");
+        let ws_addr: Option<SocketAddr> = m
+            .value_of("electrum_ws_addr")
+            .map(|addr| addr.parse().expect("invalid Electrum WebSocket address"));
+        let http_addr: Option<SocketAddr> = m
+            .value_of("http_addr")
+            .map(|addr| addr.parse().expect("invalid HTTP REST API address"));
         let monitoring_addr: SocketAddr = m
             .value_of("monitoring_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port))
@@ -165,16 +296,39 @@ impl Config {
             db_path,
             daemon_dir,
             daemon_rpc_addr,
+            daemon_p2p_addr,
+            daemon_p2p_notify: m.is_present("daemon_p2p_notify"),
+            daemon_p2p_blocks: m.is_present("daemon_p2p_blocks"),
             cookie,
             electrum_rpc_addr,
+            electrum_ssl_addr,
+            ssl_cert: m.value_of("ssl_cert").map(PathBuf::from),
+            ssl_key: m.value_of("ssl_key").map(PathBuf::from),
+            ws_addr,
+            http_addr,
             monitoring_addr,
             skip_bulk_import: m.is_present("skip_bulk_import"),
             index_batch_size: value_t_or_exit!(m, "index_batch_size", usize),
+            tx_cache_size: value_t_or_exit!(m, "tx_cache_size_mb", usize) << 20,
+            status_cache_size: value_t_or_exit!(m, "status_cache_size", usize),
+            max_connections_per_ip: value_t_or_exit!(m, "max_connections_per_ip", usize),
+            max_subscriptions: value_t_or_exit!(m, "max_subscriptions", usize),
+            max_request_size: value_t_or_exit!(m, "max_request_size", usize),
+            max_request_rate: value_t_or_exit!(m, "max_request_rate", usize),
+            max_history_size: value_t_or_exit!(m, "max_history_size", usize),
         };
         eprintln!("{:?}", config);
         config
     }
 
+    pub fn p2p_blocks_addr(&self) -> Option<SocketAddr> {
+        if self.daemon_p2p_blocks {
+            Some(self.daemon_p2p_addr)
+        } else {
+            None
+        }
+    }
+
     pub fn cookie_getter(&self) -> Arc<CookieGetter> {
         if let Some(ref value) = self.cookie {
             Arc::new(StaticCookie {
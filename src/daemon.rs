@@ -4,6 +4,7 @@ use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::network::serialize::BitcoinHash;
 use bitcoin::network::serialize::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
+use error_chain::ChainedError;
 use glob;
 use hex;
 use serde_json::{from_str, from_value, Value};
@@ -15,6 +16,7 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use metrics::{HistogramOpts, HistogramVec, Metrics};
+use notify;
 use signal::Waiter;
 use util::HeaderList;
 
@@ -27,6 +29,16 @@ pub enum Network {
     Regtest,
 }
 
+impl Network {
+    pub fn magic(&self) -> u32 {
+        match *self {
+            Network::Mainnet => 0xD9B4BEF9,
+            Network::Testnet => 0x0709110B,
+            Network::Regtest => 0xDAB5BFFA,
+        }
+    }
+}
+
 fn parse_hash(value: &Value) -> Result<Sha256dHash> {
     Ok(Sha256dHash::from_hex(
         value
@@ -70,6 +82,9 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
                         _ => (),
                     }
+                    if let Some(msg) = err["message"].as_str() {
+                        bail!(ErrorKind::Daemon(code, msg.to_owned()));
+                    }
                 }
                 bail!("{} RPC error: {}", method, err);
             }
@@ -118,7 +133,7 @@ pub struct MempoolEntry {
 }
 
 impl MempoolEntry {
-    fn new(fee: u64, vsize: u32) -> MempoolEntry {
+    pub fn new(fee: u64, vsize: u32) -> MempoolEntry {
         MempoolEntry {
             fee,
             vsize,
@@ -263,7 +278,9 @@ pub struct Daemon {
     daemon_dir: PathBuf,
     network: Network,
     conn: Mutex<Connection>,
-    message_id: Counter, // for monotonic JSONRPC 'id'
+    message_id: Counter,          // for monotonic JSONRPC 'id'
+    p2p_addr: Option<SocketAddr>, // for fetching blocks (otherwise, JSONRPC is used)
+    p2p: Arc<Mutex<Option<notify::Connection>>>, // shared by reconnected instances
     signal: Waiter,
 
     // monitoring
@@ -275,6 +292,7 @@ impl Daemon {
     pub fn new(
         daemon_dir: &PathBuf,
         daemon_rpc_addr: SocketAddr,
+        daemon_p2p_addr: Option<SocketAddr>,
         cookie_getter: Arc<CookieGetter>,
         network: Network,
         signal: Waiter,
@@ -289,6 +307,8 @@ impl Daemon {
                 signal.clone(),
             )?),
             message_id: Counter::new(),
+            p2p_addr: daemon_p2p_addr,
+            p2p: Arc::new(Mutex::new(None)),
             signal,
             latency: metrics.histogram_vec(
                 HistogramOpts::new("daemon_rpc", "Bitcoind RPC latency (in seconds)"),
@@ -326,6 +346,8 @@ impl Daemon {
             network: self.network,
             conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
             message_id: Counter::new(),
+            p2p_addr: self.p2p_addr,
+            p2p: self.p2p.clone(),
             signal: self.signal.clone(),
             latency: self.latency.clone(),
             size: self.size.clone(),
@@ -346,11 +368,7 @@ impl Daemon {
     }
 
     pub fn magic(&self) -> u32 {
-        match self.network {
-            Network::Mainnet => 0xD9B4BEF9,
-            Network::Testnet => 0x0709110B,
-            Network::Regtest => 0xDAB5BFFA,
-        }
+        self.network.magic()
     }
 
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
@@ -459,6 +477,12 @@ impl Daemon {
     }
 
     pub fn getblocks(&self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        if let Some(addr) = self.p2p_addr {
+            match self.getblocks_p2p(addr, blockhashes) {
+                Ok(blocks) => return Ok(blocks),
+                Err(e) => warn!("falling back to JSONRPC: {}", e.display_chain()),
+            }
+        }
         let params_list: Vec<Value> = blockhashes
             .iter()
             .map(|hash| json!([hash.be_hex_string(), /*verbose=*/ false]))
@@ -471,6 +495,24 @@ impl Daemon {
         Ok(blocks)
     }
 
+    fn getblocks_p2p(&self, addr: SocketAddr, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        let mut p2p = self.p2p.lock().unwrap();
+        if p2p.is_none() {
+            *p2p = Some(notify::Connection::connect(
+                self.network,
+                addr,
+                /*relay=*/ false,
+            )?);
+        }
+        let timer = self.latency.with_label_values(&["getdata"]).start_timer();
+        let result = p2p.as_mut().unwrap().getblocks(blockhashes);
+        timer.observe_duration();
+        if result.is_err() {
+            *p2p = None; // reconnect on the next request
+        }
+        result.chain_err(|| format!("failed to fetch {} blocks over P2P", blockhashes.len()))
+    }
+
     pub fn gettransaction(
         &self,
         txhash: &Sha256dHash,
@@ -15,5 +15,30 @@ error_chain!{
             description("Interruption by external signal")
             display("Iterrupted by SIG{:?}", signal)
         }
+
+        Daemon(code: i64, msg: String) {
+            description("Daemon RPC error")
+            display("Daemon RPC error {}: {}", code, msg)
+        }
+
+        MethodNotFound(msg: String) {
+            description("Method not found")
+            display("{}", msg)
+        }
+
+        InvalidRequest(msg: String) {
+            description("Invalid request")
+            display("{}", msg)
+        }
+
+        InvalidParams(msg: String) {
+            description("Invalid params")
+            display("{}", msg)
+        }
+
+        LimitExceeded(limit: &'static str, msg: String) {
+            description("Resource limit exceeded")
+            display("{}", msg)
+        }
     }
 }
@@ -1,4 +1,8 @@
-use store::{ReadStore, Row, WriteStore};
+use std::collections::BTreeMap;
+use std::iter;
+use std::sync::RwLock;
+
+use store::{ReadStore, Row, ScanIterator, WriteStore};
 use util::Bytes;
 
 pub struct FakeStore;
@@ -7,12 +11,55 @@ impl ReadStore for FakeStore {
     fn get(&self, _key: &[u8]) -> Option<Bytes> {
         None
     }
-    fn scan(&self, _prefix: &[u8]) -> Vec<Row> {
-        vec![]
+    fn iter_scan<'a>(&'a self, _prefix: &[u8]) -> ScanIterator<'a> {
+        Box::new(iter::empty())
     }
 }
 
 impl WriteStore for FakeStore {
     fn write(&self, _rows: Vec<Row>) {}
+    fn delete(&self, _keys: Vec<Bytes>) {}
+    fn delete_and_write(&self, _keys: Vec<Bytes>, _rows: Vec<Row>) {}
+    fn flush(&self) {}
+}
+
+/// In-memory store (e.g. for testing).
+#[derive(Default)]
+pub struct MemStore {
+    map: RwLock<BTreeMap<Bytes, Bytes>>,
+}
+
+impl ReadStore for MemStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.map.read().unwrap().get(key).cloned()
+    }
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> ScanIterator<'a> {
+        let map = self.map.read().unwrap();
+        let rows: Vec<Row> = map
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Row {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        Box::new(rows.into_iter())
+    }
+}
+
+impl WriteStore for MemStore {
+    fn write(&self, rows: Vec<Row>) {
+        self.delete_and_write(vec![], rows);
+    }
+    fn delete(&self, keys: Vec<Bytes>) {
+        self.delete_and_write(keys, vec![]);
+    }
+    fn delete_and_write(&self, keys: Vec<Bytes>, rows: Vec<Row>) {
+        let mut map = self.map.write().unwrap();
+        for key in keys {
+            map.remove(&key);
+        }
+        map.extend(rows.into_iter().map(Row::into_pair));
+    }
     fn flush(&self) {}
 }
@@ -8,7 +8,7 @@ use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 use daemon::Daemon;
 use metrics::{Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
@@ -25,12 +25,12 @@ use errors::*;
 pub struct TxInKey {
     pub code: u8,
     pub prev_hash_prefix: HashPrefix,
-    pub prev_index: u16,
+    pub prev_index: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TxInRow {
-    key: TxInKey,
+    pub key: TxInKey,
     pub txid_prefix: HashPrefix,
 }
 
@@ -40,7 +40,7 @@ impl TxInRow {
             key: TxInKey {
                 code: b'I',
                 prev_hash_prefix: hash_prefix(&input.prev_hash[..]),
-                prev_index: input.prev_index as u16,
+                prev_index: input.prev_index,
             },
             txid_prefix: hash_prefix(&txid[..]),
         }
@@ -50,7 +50,7 @@ impl TxInRow {
         bincode::serialize(&TxInKey {
             code: b'I',
             prev_hash_prefix: hash_prefix(&txid[..]),
-            prev_index: output_index as u16,
+            prev_index: output_index as u32,
         }).unwrap()
     }
 
@@ -156,9 +156,122 @@ impl TxRow {
 #[derive(Serialize, Deserialize)]
 pub struct BlockKey {
     code: u8,
-    hash: FullHash,
+    pub hash: FullHash,
 }
 
+impl BlockKey {
+    fn new(blockhash: &Sha256dHash) -> BlockKey {
+        BlockKey {
+            code: b'B',
+            hash: full_hash(&blockhash[..]),
+        }
+    }
+
+    fn to_key(&self) -> Bytes {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlockTxidsKey {
+    code: u8,
+    pub hash: FullHash,
+}
+
+// Ordered transaction IDs of a block, for serving merkle proofs without fetching the block.
+pub struct BlockTxidsRow {
+    pub key: BlockTxidsKey,
+    pub txids: Vec<FullHash>, // value
+}
+
+impl BlockTxidsRow {
+    pub fn new(block: &Block) -> BlockTxidsRow {
+        BlockTxidsRow {
+            key: BlockTxidsKey {
+                code: b'M',
+                hash: full_hash(&block.bitcoin_hash()[..]),
+            },
+            txids: block
+                .txdata
+                .iter()
+                .map(|txn| full_hash(&txn.txid()[..]))
+                .collect(),
+        }
+    }
+
+    pub fn filter(blockhash: &Sha256dHash) -> Bytes {
+        bincode::serialize(&BlockTxidsKey {
+            code: b'M',
+            hash: full_hash(&blockhash[..]),
+        }).unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.txids).unwrap(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> BlockTxidsRow {
+        BlockTxidsRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse BlockTxidsKey"),
+            txids: bincode::deserialize(&row.value).expect("failed to parse block txids"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UndoKey {
+    code: u8,
+    pub hash: FullHash,
+}
+
+// Keys written by `index_block`, allowing the block to be disconnected after a reorg.
+pub struct UndoRow {
+    pub key: UndoKey,
+    keys: Vec<Bytes>, // value
+}
+
+impl UndoRow {
+    pub fn new(blockhash: &Sha256dHash, rows: &[Row]) -> UndoRow {
+        UndoRow {
+            key: UndoKey {
+                code: b'U',
+                hash: full_hash(&blockhash[..]),
+            },
+            keys: rows.iter().map(|row| row.key.clone()).collect(),
+        }
+    }
+
+    pub fn filter(blockhash: &Sha256dHash) -> Bytes {
+        bincode::serialize(&UndoKey {
+            code: b'U',
+            hash: full_hash(&blockhash[..]),
+        }).unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.keys).unwrap(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> UndoRow {
+        UndoRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse UndoKey"),
+            keys: bincode::deserialize(&row.value).expect("failed to parse undo keys"),
+        }
+    }
+}
+
+// Undo data is kept only for the latest blocks (deeper reorgs re-fetch the stale blocks).
+const UNDO_DEPTH: usize = 100;
+
+// Indexing more blocks (e.g. during initial sync) is reported as touching all script hashes.
+const MAX_TOUCHED_BLOCKS: usize = 10;
+
 pub fn compute_script_hash(data: &[u8]) -> FullHash {
     let mut hash = FullHash::default();
     let mut sha2 = Sha256::new();
@@ -167,6 +280,55 @@ pub fn compute_script_hash(data: &[u8]) -> FullHash {
     hash
 }
 
+/// Script hashes and spent outputs affected by new (or removed) transactions.
+#[derive(Default)]
+pub struct Touched {
+    pub all: bool, // e.g. after a reorg
+    pub script_hashes: HashSet<FullHash>,
+    pub outpoints: HashSet<(Sha256dHash, usize)>, // (txid, output index) spent by the transactions
+}
+
+impl Touched {
+    pub fn all() -> Touched {
+        Touched {
+            all: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.script_hashes.is_empty() && self.outpoints.is_empty()
+    }
+
+    pub fn add_transaction(&mut self, txn: &Transaction) {
+        if self.all {
+            return;
+        }
+        let null_hash = Sha256dHash::default();
+        for input in &txn.input {
+            if input.prev_hash != null_hash {
+                self.outpoints
+                    .insert((input.prev_hash, input.prev_index as usize));
+            }
+        }
+        for output in &txn.output {
+            self.script_hashes
+                .insert(compute_script_hash(&output.script_pubkey[..]));
+        }
+    }
+
+    pub fn extend(&mut self, other: Touched) {
+        if other.all {
+            *self = Touched::all();
+        }
+        if self.all {
+            return;
+        }
+        self.script_hashes.extend(other.script_hashes);
+        self.outpoints.extend(other.outpoints);
+    }
+}
+
 pub fn index_transaction(txn: &Transaction, height: usize, rows: &mut Vec<Row>) {
     let null_hash = Sha256dHash::default();
     let txid: Sha256dHash = txn.txid();
@@ -188,18 +350,90 @@ pub fn index_block(block: &Block, height: usize) -> Vec<Row> {
     for txn in &block.txdata {
         index_transaction(&txn, height, &mut rows);
     }
+    rows.push(BlockTxidsRow::new(block).to_row());
     let blockhash = block.bitcoin_hash();
     // Persist block hash and header
     rows.push(Row {
-        key: bincode::serialize(&BlockKey {
-            code: b'B',
-            hash: full_hash(&blockhash[..]),
-        }).unwrap(),
+        key: BlockKey::new(&blockhash).to_key(),
         value: serialize(&block.header).unwrap(),
     });
     rows
 }
 
+/// Also returns the block's undo data, and marks it as the last indexed block.
+pub fn index_block_with_undo(block: &Block, height: usize) -> Vec<Row> {
+    let blockhash = block.bitcoin_hash();
+    let mut rows = index_block(block, height);
+    rows.push(UndoRow::new(&blockhash, &rows).to_row());
+    rows.push(last_indexed_block(&blockhash));
+    rows
+}
+
+fn read_undo_keys(store: &ReadStore, blockhash: &Sha256dHash) -> Option<Vec<Bytes>> {
+    let key = UndoRow::filter(blockhash);
+    let value = store.get(&key)?;
+    Some(UndoRow::from_row(&Row { key, value }).keys)
+}
+
+// Remove the rows written by the last indexed block (given their keys).
+// Its parent is marked as the last indexed block in the same batch, so no rows are left behind.
+fn disconnect_block(store: &WriteStore, entry: &HeaderEntry, mut keys: Vec<Bytes>) {
+    keys.push(BlockKey::new(entry.hash()).to_key());
+    keys.push(UndoRow::filter(entry.hash()));
+    store.delete_and_write(
+        keys,
+        vec![last_indexed_block(&entry.header().prev_blockhash)],
+    );
+}
+
+// Remove the rows of indexed blocks at [height..), starting from the tip.
+fn disconnect_blocks<F>(
+    store: &WriteStore,
+    indexed_headers: &HeaderList,
+    height: usize,
+    mut undo_keys: F,
+) -> Result<()>
+where
+    F: FnMut(&HeaderEntry) -> Result<Vec<Bytes>>,
+{
+    let stale_headers: Vec<&HeaderEntry> = indexed_headers.iter().skip(height).collect();
+    for entry in stale_headers.into_iter().rev() {
+        info!("disconnecting {:?}", entry);
+        let keys = undo_keys(entry)?;
+        disconnect_block(store, entry, keys);
+    }
+    Ok(())
+}
+
+// Returns the height of the first indexed block that is not part of bitcoind's best chain.
+fn fork_height(
+    indexed_headers: &HeaderList,
+    new_headers: &[HeaderEntry],
+    tip: &Sha256dHash,
+) -> Result<usize> {
+    Ok(match new_headers.first() {
+        Some(entry) => entry.height(),
+        None => {
+            indexed_headers
+                .header_by_blockhash(tip)
+                .chain_err(|| format!("missing tip {}", tip))?
+                .height() + 1
+        }
+    })
+}
+
+// Replaces the indexed headers at [fork_height..) by the new ones.
+fn replace_headers(
+    indexed_headers: &HeaderList,
+    fork_height: usize,
+    new_headers: Vec<HeaderEntry>,
+) -> HeaderList {
+    let mut headers = indexed_headers.clone();
+    headers.truncate(fork_height);
+    headers.apply(new_headers);
+    headers
+}
+
 pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
     // Store last indexed block (i.e. all previous blocks were indexed)
     Row {
@@ -210,7 +444,7 @@ pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
 
 pub fn read_indexed_blockhashes(store: &ReadStore) -> HashSet<Sha256dHash> {
     let mut result = HashSet::new();
-    for row in store.scan(b"B") {
+    for row in store.iter_scan(b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         result.insert(deserialize(&key.hash).unwrap());
     }
@@ -224,7 +458,7 @@ fn read_indexed_headers(store: &ReadStore) -> HeaderList {
         None => Sha256dHash::default(),
     };
     let mut map = HeaderMap::new();
-    for row in store.scan(b"B") {
+    for row in store.iter_scan(b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         let header: BlockHeader = deserialize(&row.value).unwrap();
         map.insert(deserialize(&key.hash).unwrap(), header);
@@ -300,8 +534,7 @@ impl Stats {
 }
 
 pub struct Index {
-    // TODO: store also latest snapshot.
-    headers: RwLock<HeaderList>,
+    headers: RwLock<Arc<HeaderList>>, // replaced (not modified) after indexing new blocks
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
@@ -318,7 +551,7 @@ impl Index {
         let headers = read_indexed_headers(store);
         stats.height.set((headers.len() as i64) - 1);
         Ok(Index {
-            headers: RwLock::new(headers),
+            headers: RwLock::new(Arc::new(headers)),
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
@@ -326,30 +559,64 @@ impl Index {
     }
 
     pub fn reload(&self, store: &ReadStore) {
-        let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+        *self.headers.write().unwrap() = Arc::new(read_indexed_headers(store));
     }
 
-    pub fn best_header(&self) -> Option<HeaderEntry> {
-        let headers = self.headers.read().unwrap();
-        headers.header_by_blockhash(headers.tip()).cloned()
+    /// Returns the headers of the indexed blocks (their rows are all written to the DB).
+    pub fn headers(&self) -> Arc<HeaderList> {
+        self.headers.read().unwrap().clone()
     }
 
-    pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
-        self.headers
-            .read()
-            .unwrap()
-            .header_by_height(height)
-            .cloned()
+    fn undo_keys(
+        &self,
+        store: &ReadStore,
+        daemon: &Daemon,
+        entry: &HeaderEntry,
+    ) -> Result<Vec<Bytes>> {
+        if let Some(keys) = read_undo_keys(store, entry.hash()) {
+            return Ok(keys);
+        }
+        // No undo data was persisted (e.g. bulk-indexed or old block), so re-index it instead.
+        let block = daemon
+            .getblock(entry.hash())
+            .chain_err(|| format!("failed to get stale block {}", entry.hash()))?;
+        Ok(index_block(&block, entry.height())
+            .into_iter()
+            .map(|row| row.key)
+            .collect())
     }
 
-    pub fn update(&self, store: &WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
+    // The indexed headers are truncated only after the new branch is indexed (see `update`).
+    fn disconnect(&self, store: &WriteStore, daemon: &Daemon, height: usize) -> Result<()> {
+        let indexed_headers = self.headers();
+        disconnect_blocks(store, &indexed_headers, height, |entry| {
+            self.undo_keys(store, daemon, entry)
+        })?;
+        if height < indexed_headers.len() {
+            self.stats.height.set(height as i64 - 1);
+        }
+        Ok(())
+    }
+
+    /// Indexes the blocks up to bitcoind's best block, returning its hash and the touched script
+    /// hashes (for invalidating cached statuses).
+    pub fn update(&self, store: &WriteStore, waiter: &Waiter) -> Result<(Sha256dHash, Touched)> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers: Vec<HeaderEntry> = {
-            let indexed_headers = self.headers.read().unwrap();
-            indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
+        let (new_headers, fork_height, indexed_height) = {
+            let indexed_headers = self.headers();
+            let new_headers =
+                indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?);
+            let fork_height = fork_height(&indexed_headers, &new_headers, &tip)?;
+            (new_headers, fork_height, indexed_headers.len())
+        };
+        let mut touched = if fork_height < indexed_height || new_headers.len() > MAX_TOUCHED_BLOCKS
+        {
+            Touched::all()
+        } else {
+            Touched::default()
         };
+        self.disconnect(store, &daemon, fork_height)?;
         new_headers.last().map(|tip| {
             info!("{:?} ({} left to index)", tip, new_headers.len());
         });
@@ -391,11 +658,12 @@ impl Index {
                     .expect(&format!("missing header for block {}", blockhash));
 
                 let timer = self.stats.start_timer("index");
-                let mut block_rows = index_block(block, height);
-                block_rows.push(last_indexed_block(&blockhash));
-                rows.extend(block_rows);
+                rows.extend(index_block_with_undo(block, height));
                 timer.observe_duration();
                 self.stats.update(block, height);
+                for txn in &block.txdata {
+                    touched.add_transaction(txn);
+                }
             }
             let timer = self.stats.start_timer("write");
             store.write(rows);
@@ -406,8 +674,290 @@ impl Index {
         timer.observe_duration();
 
         fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(new_headers);
-        assert_eq!(tip, *self.headers.read().unwrap().tip());
-        Ok(tip)
+        let new_heights: Vec<usize> = new_headers.iter().map(|h| h.height()).collect();
+        // replace the stale headers (if any) only now, so queries never see a shorter chain
+        let indexed_headers = replace_headers(&self.headers(), fork_height, new_headers);
+        assert_eq!(tip, *indexed_headers.tip());
+        *self.headers.write().unwrap() = Arc::new(indexed_headers);
+
+        let stale_undo_keys: Vec<Bytes> = {
+            let indexed_headers = self.headers();
+            new_heights
+                .into_iter()
+                .filter_map(|height| height.checked_sub(UNDO_DEPTH))
+                .filter_map(|height| indexed_headers.header_by_height(height))
+                .map(|entry| UndoRow::filter(entry.hash()))
+                .collect()
+        };
+        store.delete(stale_undo_keys);
+        Ok((tip, touched))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::network::serialize::{deserialize, BitcoinHash};
+    use bitcoin::util::hash::Sha256dHash;
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use fake::MemStore;
+
+    fn tx(inputs: &[(Sha256dHash, u32)], outputs: &[(u64, &Script)]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|&(prev_hash, prev_index)| TxIn {
+                    prev_hash,
+                    prev_index,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|&(value, script)| TxOut {
+                    value,
+                    script_pubkey: script.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn coinbase(height: u32, script: &Script) -> Transaction {
+        let mut txn = tx(&[(Sha256dHash::default(), 0xffff_ffff)], &[(50, script)]);
+        txn.lock_time = height; // make coinbase txids unique
+        txn
+    }
+
+    fn genesis(txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: Sha256dHash::default(),
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    fn block(prev: &Block, nonce: u32, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce,
+            },
+            txdata,
+        }
+    }
+
+    fn connect(store: &MemStore, block: &Block) {
+        let height = read_indexed_headers(store).len();
+        store.write(index_block_with_undo(block, height));
+    }
+
+    fn disconnect(store: &MemStore) {
+        let headers = read_indexed_headers(store);
+        let entry = headers.header_by_height(headers.len() - 1).unwrap();
+        let keys = read_undo_keys(store, entry.hash()).expect("missing undo row");
+        disconnect_block(store, entry, keys);
+    }
+
+    // Returns the (confirmed) balance and history of `script`, following the rows like queries do.
+    fn status(
+        store: &MemStore,
+        txns: &HashMap<Sha256dHash, Transaction>,
+        script: &Script,
+    ) -> (u64, HashSet<(Sha256dHash, u32)>) {
+        let lookup = |txid_prefix: &HashPrefix| -> Vec<(Sha256dHash, u32)> {
+            store
+                .scan(&TxRow::filter_prefix(txid_prefix))
+                .iter()
+                .map(TxRow::from_row)
+                .map(|row| (deserialize(&row.key.txid).unwrap(), row.height))
+                .collect()
+        };
+        let mut balance = 0;
+        let mut history = HashSet::new();
+        let funding_rows = store.scan(&TxOutRow::filter(&compute_script_hash(&script[..])));
+        for row in funding_rows.iter().map(TxOutRow::from_row) {
+            for (txid, height) in lookup(&row.txid_prefix) {
+                history.insert((txid, height));
+                for (index, output) in txns[&txid].output.iter().enumerate() {
+                    if output.script_pubkey != *script {
+                        continue;
+                    }
+                    let spending_rows = store.scan(&TxInRow::filter(&txid, index));
+                    if spending_rows.is_empty() {
+                        balance += output.value;
+                    }
+                    for row in spending_rows.iter().map(TxInRow::from_row) {
+                        history.extend(lookup(&row.txid_prefix));
+                    }
+                }
+            }
+        }
+        (balance, history)
+    }
+
+    #[test]
+    fn test_reorg() {
+        let alice = Script::from(vec![0x51]);
+        let bob = Script::from(vec![0x52]);
+        let miner = Script::from(vec![0x53]);
+
+        let genesis = genesis(vec![coinbase(0, &alice)]);
+        let funding = genesis.txdata[0].txid();
+        // alice pays 30 to bob (and 20 back to herself)
+        let block1 = block(
+            &genesis,
+            1,
+            vec![
+                coinbase(1, &miner),
+                tx(&[(funding, 0)], &[(30, &bob), (20, &alice)]),
+            ],
+        );
+        // ... but after a reorg, she pays all 50 to bob
+        let block1b = block(
+            &genesis,
+            2,
+            vec![coinbase(1, &miner), tx(&[(funding, 0)], &[(50, &bob)])],
+        );
+        let txns: HashMap<Sha256dHash, Transaction> = [&genesis, &block1, &block1b]
+            .iter()
+            .flat_map(|b| b.txdata.iter())
+            .map(|txn| (txn.txid(), txn.clone()))
+            .collect();
+        let history = |blk: &Block, height: u32| -> HashSet<(Sha256dHash, u32)> {
+            blk.txdata[1..]
+                .iter()
+                .map(|txn| (txn.txid(), height))
+                .chain(vec![(funding, 0)])
+                .collect()
+        };
+
+        let store = MemStore::default();
+        connect(&store, &genesis);
+        connect(&store, &block1);
+        assert_eq!(status(&store, &txns, &alice), (20, history(&block1, 1)));
+        assert_eq!(status(&store, &txns, &bob).0, 30);
+
+        disconnect(&store);
+        assert_eq!(*read_indexed_headers(&store).tip(), genesis.bitcoin_hash());
+        assert_eq!(status(&store, &txns, &alice), (50, history(&genesis, 0)));
+        assert_eq!(status(&store, &txns, &bob), (0, HashSet::new()));
+
+        connect(&store, &block1b);
+        assert_eq!(status(&store, &txns, &alice), (0, history(&block1b, 1)));
+        assert_eq!(status(&store, &txns, &bob).0, 50);
+
+        disconnect(&store);
+        connect(&store, &block1);
+        assert_eq!(*read_indexed_headers(&store).tip(), block1.bitcoin_hash());
+        assert_eq!(status(&store, &txns, &alice), (20, history(&block1, 1)));
+        assert_eq!(status(&store, &txns, &bob).0, 30);
+        assert!(read_undo_keys(&store, &block1b.bitcoin_hash()).is_none());
+    }
+
+    #[test]
+    fn test_fork_and_replace_headers() {
+        let genesis = genesis(vec![coinbase(0, &Script::new())]);
+        let block1 = block(&genesis, 1, vec![coinbase(1, &Script::new())]);
+        let block2 = block(&block1, 1, vec![coinbase(2, &Script::new())]);
+        let miner = Script::from(vec![0x51]); // so the new branch has other coinbase txids
+        let block1b = block(&genesis, 2, vec![coinbase(1, &miner)]);
+        let block2b = block(&block1b, 2, vec![coinbase(2, &miner)]);
+        let block3b = block(&block2b, 2, vec![coinbase(3, &miner)]);
+
+        let store = MemStore::default();
+        for blk in &[&genesis, &block1, &block2] {
+            connect(&store, blk);
+        }
+        let indexed_headers = read_indexed_headers(&store);
+        let undo_keys = |entry: &HeaderEntry| -> Result<Vec<Bytes>> {
+            read_undo_keys(&store, entry.hash()).chain_err(|| "missing undo row")
+        };
+
+        // bitcoind's tip moved back to block1 (e.g. after `invalidateblock`)
+        assert_eq!(
+            fork_height(&indexed_headers, &[], &block1.bitcoin_hash()).unwrap(),
+            2
+        );
+        assert!(fork_height(&indexed_headers, &[], &block3b.bitcoin_hash()).is_err());
+
+        // a longer branch forks after genesis
+        let new_headers = indexed_headers.order(
+            [&block1b, &block2b, &block3b]
+                .iter()
+                .map(|blk| blk.header)
+                .collect(),
+        );
+        let height = fork_height(&indexed_headers, &new_headers, &block3b.bitcoin_hash()).unwrap();
+        assert_eq!(height, 1);
+        disconnect_blocks(&store, &indexed_headers, height, undo_keys).unwrap();
+        assert_eq!(*read_indexed_headers(&store).tip(), genesis.bitcoin_hash());
+        for (entry, blk) in new_headers.iter().zip(&[&block1b, &block2b, &block3b]) {
+            assert_eq!(*entry.hash(), blk.bitcoin_hash());
+            store.write(index_block_with_undo(blk, entry.height()));
+        }
+        let headers = replace_headers(&indexed_headers, height, new_headers);
+        assert_eq!(*headers.tip(), block3b.bitcoin_hash());
+        assert_eq!(headers.len(), 4);
+        assert!(headers.equals(&read_indexed_headers(&store)));
+
+        // no rows of the stale blocks are left behind
+        let blockhashes: HashSet<Sha256dHash> = [&genesis, &block1b, &block2b, &block3b]
+            .iter()
+            .map(|blk| blk.bitcoin_hash())
+            .collect();
+        assert_eq!(read_indexed_blockhashes(&store), blockhashes);
+        for blk in &[&block1, &block2] {
+            assert!(read_undo_keys(&store, &blk.bitcoin_hash()).is_none());
+            let coinbase_txid = blk.txdata[0].txid();
+            assert!(store.scan(&TxRow::filter_full(&coinbase_txid)).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_large_output_index() {
+        let funding = coinbase(0, &Script::new()).txid();
+        let low = tx(&[(funding, 5)], &[]);
+        let high = tx(&[(funding, 5 + 0x1_0000)], &[]);
+        let last = tx(&[(funding, 0xffff_fffe)], &[]);
+
+        let row = TxInRow::from_row(&TxInRow::new(&last.txid(), &last.input[0]).to_row());
+        assert_eq!(row.key.prev_index, 0xffff_fffe);
+
+        let store = MemStore::default();
+        let mut rows = vec![];
+        for txn in &[&low, &high, &last] {
+            index_transaction(txn, 1, &mut rows);
+        }
+        store.write(rows);
+        let spending = |index: usize| -> Vec<HashPrefix> {
+            store
+                .scan(&TxInRow::filter(&funding, index))
+                .iter()
+                .map(|row| TxInRow::from_row(row).txid_prefix)
+                .collect()
+        };
+        assert_eq!(spending(5), vec![hash_prefix(&low.txid()[..])]);
+        assert_eq!(spending(5 + 0x1_0000), vec![hash_prefix(&high.txid()[..])]);
+        assert_eq!(spending(0xffff_fffe), vec![hash_prefix(&last.txid()[..])]);
+        assert!(spending(6).is_empty());
+        assert!(spending(0xfffe).is_empty());
     }
 }
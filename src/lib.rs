@@ -9,6 +9,9 @@ extern crate dirs;
 extern crate glob;
 extern crate hex;
 extern crate libc;
+extern crate lru;
+extern crate mio;
+extern crate openssl;
 extern crate page_size;
 extern crate prometheus;
 extern crate rocksdb;
@@ -35,6 +38,7 @@ extern crate serde_json;
 
 pub mod app;
 pub mod bulk;
+pub mod check;
 pub mod config;
 pub mod daemon;
 pub mod errors;
@@ -42,9 +46,13 @@ pub mod fake;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod migrate;
 pub mod notify;
 pub mod query;
+pub mod rest;
 pub mod rpc;
 pub mod signal;
 pub mod store;
+pub mod tls;
 pub mod util;
+pub mod ws;
@@ -5,12 +5,12 @@ use std::collections::BTreeMap;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::ops::Bound;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
 
 use daemon::{Daemon, MempoolEntry};
-use index::index_transaction;
+use index::{index_transaction, Touched};
 use metrics::{Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
-use store::{ReadStore, Row};
+use store::{ReadStore, Row, ScanIterator};
 use util::Bytes;
 
 use errors::*;
@@ -73,28 +73,47 @@ impl ReadStore for MempoolStore {
         let map = self.map.read().unwrap();
         Some(map.get(key)?.last()?.to_vec())
     }
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let map = self.map.read().unwrap();
-        let range = map.range((Bound::Included(prefix.to_vec()), Bound::Unbounded));
-        let mut rows = vec![];
-        for (key, values) in range {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            if let Some(value) = values.last() {
-                rows.push(Row {
-                    key: key.to_vec(),
-                    value: value.to_vec(),
-                });
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> ScanIterator<'a> {
+        Box::new(MempoolScan {
+            map: self.map.read().unwrap(),
+            prefix: prefix.to_vec(),
+            start: Bound::Included(prefix.to_vec()),
+        })
+    }
+}
+
+// Holds the read lock until the scan is dropped, so it sees a consistent view of the mempool.
+struct MempoolScan<'a> {
+    map: RwLockReadGuard<'a, BTreeMap<Bytes, Vec<Bytes>>>,
+    prefix: Bytes,
+    start: Bound<Bytes>,
+}
+
+impl<'a> Iterator for MempoolScan<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        let row = {
+            let prefix = &self.prefix;
+            let (key, values) = self
+                .map
+                .range((self.start.clone(), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .find(|(_, values)| !values.is_empty())?;
+            Row {
+                key: key.to_vec(),
+                value: values.last().unwrap().to_vec(),
             }
-        }
-        rows
+        };
+        self.start = Bound::Excluded(row.key.clone());
+        Some(row)
     }
 }
 
 struct Item {
-    tx: Transaction,     // stored for faster retrieval and index removal
-    entry: MempoolEntry, // caches mempool fee rates
+    tx: Transaction,          // stored for faster retrieval and index removal
+    entry: MempoolEntry,      // caches mempool fee rates
+    unconfirmed_inputs: bool, // spends an output of another mempool transaction
 }
 
 struct Stats {
@@ -177,6 +196,17 @@ impl Tracker {
         self.items.get(txid).map(|stats| stats.tx.clone())
     }
 
+    pub fn get_fee(&self, txid: &Sha256dHash) -> Option<u64> {
+        self.items.get(txid).map(|stats| stats.entry.fee())
+    }
+
+    /// Returns true if the transaction spends an output of another mempool transaction.
+    pub fn has_unconfirmed_inputs(&self, txid: &Sha256dHash) -> bool {
+        self.items
+            .get(txid)
+            .map_or(false, |stats| stats.unconfirmed_inputs)
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -188,7 +218,9 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
+    /// Returns the script hashes touched by added or removed transactions.
+    pub fn update(&mut self, daemon: &Daemon) -> Result<Touched> {
+        let mut touched = Touched::default();
         let timer = self.stats.start_timer("fetch");
         let new_txids = daemon
             .getmempooltxids()
@@ -209,26 +241,33 @@ impl Tracker {
                 }
             })
             .collect();
-        if entries.is_empty() {
-            return Ok(());
-        }
-        let txids: Vec<&Sha256dHash> = entries.iter().map(|(txid, _)| *txid).collect();
-        let txs = match daemon.gettransactions(&txids) {
-            Ok(txs) => txs,
-            Err(err) => {
-                warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
-                return Ok(()); // keep the mempool until next update()
+        if !entries.is_empty() {
+            let txids: Vec<&Sha256dHash> = entries.iter().map(|(txid, _)| *txid).collect();
+            let txs = match daemon.gettransactions(&txids) {
+                Ok(txs) => txs,
+                Err(err) => {
+                    warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
+                    return Ok(touched); // keep the mempool until next update()
+                }
+            };
+            for ((txid, entry), tx) in entries.into_iter().zip(txs.into_iter()) {
+                assert_eq!(tx.txid(), *txid);
+                touched.add_transaction(&tx);
+                self.add(txid, tx, entry);
             }
-        };
-        for ((txid, entry), tx) in entries.into_iter().zip(txs.into_iter()) {
-            assert_eq!(tx.txid(), *txid);
-            self.add(txid, tx, entry);
         }
         timer.observe_duration();
 
         let timer = self.stats.start_timer("remove");
         for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+            touched.add_transaction(&self.remove(txid));
+        }
+        timer.observe_duration();
+
+        // parents may be added after their children, or removed (e.g. confirmed) before them
+        let timer = self.stats.start_timer("dependencies");
+        for txid in self.update_unconfirmed_inputs() {
+            touched.add_transaction(&self.items[&txid].tx); // its height has changed
         }
         timer.observe_duration();
 
@@ -237,20 +276,53 @@ impl Tracker {
         timer.observe_duration();
 
         self.stats.count.set(self.items.len() as i64);
-        Ok(())
+        Ok(touched)
     }
 
     fn add(&mut self, txid: &Sha256dHash, tx: Transaction, entry: MempoolEntry) {
         self.index.add(&tx);
-        self.items.insert(*txid, Item { tx, entry });
+        self.items.insert(
+            *txid,
+            Item {
+                tx,
+                entry,
+                unconfirmed_inputs: false, // set by update_unconfirmed_inputs()
+            },
+        );
     }
 
-    fn remove(&mut self, txid: &Sha256dHash) {
+    fn remove(&mut self, txid: &Sha256dHash) -> Transaction {
         let stats = self
             .items
             .remove(txid)
             .expect(&format!("missing mempool tx {}", txid));
         self.index.remove(&stats.tx);
+        stats.tx
+    }
+
+    // Returns the transactions whose inputs became confirmed (or unconfirmed).
+    fn update_unconfirmed_inputs(&mut self) -> Vec<Sha256dHash> {
+        let unconfirmed: HashSet<Sha256dHash> = self
+            .items
+            .iter()
+            .filter(|(_, stats)| {
+                stats
+                    .tx
+                    .input
+                    .iter()
+                    .any(|txin| self.items.contains_key(&txin.prev_hash))
+            })
+            .map(|(txid, _)| *txid)
+            .collect();
+        let mut changed = vec![];
+        for (txid, stats) in self.items.iter_mut() {
+            let unconfirmed_inputs = unconfirmed.contains(txid);
+            if stats.unconfirmed_inputs != unconfirmed_inputs {
+                stats.unconfirmed_inputs = unconfirmed_inputs;
+                changed.push(*txid);
+            }
+        }
+        changed
     }
 
     fn update_fee_histogram(&mut self) {
@@ -281,3 +353,68 @@ fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::util::hash::Sha256dHash;
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+
+    use super::Tracker;
+    use daemon::MempoolEntry;
+    use metrics::Metrics;
+
+    fn spend(prev_hash: Sha256dHash) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                prev_hash,
+                prev_index: 0,
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 100,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn add(tracker: &mut Tracker, tx: &Transaction) {
+        tracker.add(&tx.txid(), tx.clone(), MempoolEntry::new(1000, 100));
+    }
+
+    #[test]
+    fn test_mempool_chain() {
+        let metrics = Metrics::new("127.0.0.1:0".parse::<SocketAddr>().unwrap());
+        let mut tracker = Tracker::new(&metrics);
+        let parent = spend(Sha256dHash::from_data(b"confirmed"));
+        let child = spend(parent.txid());
+        let grandchild = spend(child.txid());
+
+        // children may be added before their parents
+        add(&mut tracker, &grandchild);
+        assert_eq!(tracker.update_unconfirmed_inputs(), vec![]);
+        add(&mut tracker, &child);
+        add(&mut tracker, &parent);
+        let changed: HashSet<Sha256dHash> =
+            tracker.update_unconfirmed_inputs().into_iter().collect();
+        let expected: HashSet<Sha256dHash> =
+            vec![child.txid(), grandchild.txid()].into_iter().collect();
+        assert_eq!(changed, expected);
+        assert!(!tracker.has_unconfirmed_inputs(&parent.txid()));
+        assert!(tracker.has_unconfirmed_inputs(&child.txid()));
+        assert!(tracker.has_unconfirmed_inputs(&grandchild.txid()));
+
+        // the parent is confirmed
+        tracker.remove(&parent.txid());
+        assert_eq!(tracker.update_unconfirmed_inputs(), vec![child.txid()]);
+        assert!(!tracker.has_unconfirmed_inputs(&child.txid()));
+        assert!(tracker.has_unconfirmed_inputs(&grandchild.txid()));
+        assert!(!tracker.has_unconfirmed_inputs(&parent.txid()));
+    }
+}
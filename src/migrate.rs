@@ -0,0 +1,324 @@
+use bincode;
+use bitcoin::blockdata::block::Block;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+use std::collections::HashSet;
+use std::fs;
+
+use bulk::parse_blocks;
+use daemon::Daemon;
+use index::{read_indexed_blockhashes, TxInKey, TxInRow};
+use store::{DBStore, ReadStore, Row, WriteStore};
+use util::{hash_prefix, Bytes, HashPrefix};
+
+use errors::*;
+
+/// Version of the index schema (see `doc/schema.md`) written by this release.
+pub const DB_VERSION: u32 = 2;
+
+struct Migration {
+    version: u32, // schema version after running this migration
+    description: &'static str,
+    run: fn(&DBStore, &Daemon) -> Result<()>,
+}
+
+// Migration #i upgrades the schema from version `MIGRATIONS[i].version - 1`.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    description: "store 32-bit output index in TxInRow",
+    run: widen_txin_rows,
+}];
+
+const MIGRATION_BATCH_SIZE: usize = 1_000_000;
+const FETCH_BATCH_SIZE: usize = 100; // blocks fetched from bitcoind at once
+
+#[derive(Serialize, Deserialize)]
+struct TxInRowV1 {
+    code: u8,
+    prev_hash_prefix: HashPrefix,
+    prev_index: u16, // lower 16 bits of the spent output index
+    txid_prefix: HashPrefix,
+}
+
+const TXIN_ROW_V1_LEN: usize = 1 + 8 + 2 + 8;
+
+fn widen_txin_rows(store: &DBStore, daemon: &Daemon) -> Result<()> {
+    widen_txin_prev_index(store)?;
+    // Version 1 truncated the output index of spends from transactions with more than 65536
+    // outputs, so their rows are re-derived from the indexed blocks.
+    let mut remaining = read_indexed_blockhashes(store);
+    let blk_files = daemon.list_blk_files()?;
+    info!(
+        "looking for truncated spends in {} blocks (using {} blk*.dat files)",
+        remaining.len(),
+        blk_files.len()
+    );
+    for path in blk_files {
+        let blob = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        for block in parse_blocks(blob, daemon.magic())? {
+            if remaining.remove(&block.bitcoin_hash()) {
+                fix_truncated_spends(store, &block);
+            }
+        }
+        debug!("scanned {:?} ({} blocks left)", path, remaining.len());
+    }
+    // e.g. if bitcoind's block files are not available locally
+    let remaining: Vec<Sha256dHash> = remaining.into_iter().collect();
+    for blockhashes in remaining.chunks(FETCH_BATCH_SIZE) {
+        for block in daemon.getblocks(blockhashes)? {
+            fix_truncated_spends(store, &block);
+        }
+    }
+    Ok(())
+}
+
+// Rewrites version 1 TxInRows using a 32-bit output index (keeping its lower 16 bits).
+fn widen_txin_prev_index(store: &WriteStore) -> Result<()> {
+    let mut old_rows = store
+        .iter_scan(b"I")
+        .filter(|row| row.key.len() == TXIN_ROW_V1_LEN); // skip already migrated rows
+    let mut count = 0;
+    loop {
+        let old_keys: Vec<Bytes> = old_rows
+            .by_ref()
+            .take(MIGRATION_BATCH_SIZE)
+            .map(|row| row.key)
+            .collect();
+        if old_keys.is_empty() {
+            break;
+        }
+        let mut new_rows = vec![];
+        for key in &old_keys {
+            let old: TxInRowV1 = bincode::deserialize(key).chain_err(|| "invalid TxInRow")?;
+            let new = TxInRow {
+                key: TxInKey {
+                    code: old.code,
+                    prev_hash_prefix: old.prev_hash_prefix,
+                    prev_index: u32::from(old.prev_index),
+                },
+                txid_prefix: old.txid_prefix,
+            };
+            new_rows.push(new.to_row());
+        }
+        count += old_keys.len();
+        store.delete_and_write(old_keys, new_rows);
+        debug!("migrated {} TxInRows", count);
+    }
+    Ok(())
+}
+
+// Replaces the (widened) rows of the block's spends from outputs above 65535.
+fn fix_truncated_spends(store: &WriteStore, block: &Block) {
+    let mut rows = vec![];
+    let mut truncated_keys = vec![];
+    for txn in block.txdata.iter().filter(|txn| !txn.is_coin_base()) {
+        let txid = txn.txid();
+        let spent: HashSet<(Sha256dHash, u32)> = txn
+            .input
+            .iter()
+            .map(|input| (input.prev_hash, input.prev_index))
+            .collect();
+        for input in txn.input.iter().filter(|input| input.prev_index > 0xffff) {
+            rows.push(TxInRow::new(&txid, input).to_row());
+            let truncated_index = input.prev_index & 0xffff;
+            if spent.contains(&(input.prev_hash, truncated_index)) {
+                continue; // the truncated row also stands for an actual spend
+            }
+            let truncated = TxInRow {
+                key: TxInKey {
+                    code: b'I',
+                    prev_hash_prefix: hash_prefix(&input.prev_hash[..]),
+                    prev_index: truncated_index,
+                },
+                txid_prefix: hash_prefix(&txid[..]),
+            };
+            truncated_keys.push(truncated.to_row().key);
+        }
+    }
+    if !rows.is_empty() {
+        debug!(
+            "fixing {} truncated spends at block {}",
+            rows.len(),
+            block.bitcoin_hash()
+        );
+        store.delete_and_write(truncated_keys, rows);
+    }
+}
+
+pub fn version_row(version: u32) -> Row {
+    Row {
+        key: b"V".to_vec(),
+        value: bincode::serialize(&version).unwrap(),
+    }
+}
+
+/// Returns the schema version of the DB (`None` for a new DB).
+pub fn read_version(store: &ReadStore) -> Result<Option<u32>> {
+    let value = match store.get(&version_row(0).key) {
+        Some(value) => value,
+        None => {
+            if store.iter_scan(b"").next().is_none() {
+                return Ok(None);
+            }
+            return Ok(Some(1)); // DBs created before schema versioning was introduced
+        }
+    };
+    let version = bincode::deserialize(&value).chain_err(|| "invalid DB version")?;
+    Ok(Some(version))
+}
+
+// Returns the migrations required for upgrading the DB (and sets the version of a new DB).
+fn pending_migrations(store: &DBStore) -> Result<Vec<&'static Migration>> {
+    let version = match read_version(store)? {
+        Some(version) => version,
+        None => {
+            debug!("new DB, using schema version {}", DB_VERSION);
+            store.put_sync(version_row(DB_VERSION));
+            return Ok(vec![]);
+        }
+    };
+    if version > DB_VERSION {
+        bail!(
+            "unsupported DB schema version {} (expected {}): please upgrade electrs or reindex",
+            version,
+            DB_VERSION
+        );
+    }
+    let migrations: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > version).collect();
+    if version < DB_VERSION && migrations.first().map(|m| m.version) != Some(version + 1) {
+        bail!(
+            "no migration from DB schema version {} to {}: please reindex",
+            version,
+            DB_VERSION
+        );
+    }
+    Ok(migrations)
+}
+
+/// Checks the schema version of the DB, which must be upgraded (see `upgrade`) if it is older.
+pub fn check_version(store: DBStore) -> Result<DBStore> {
+    if let Some(migration) = pending_migrations(&store)?.first() {
+        bail!(
+            "DB schema version {} must be upgraded to {} (by running electrs)",
+            migration.version - 1,
+            DB_VERSION
+        );
+    }
+    Ok(store)
+}
+
+/// Checks the schema version of the DB, and upgrades it in-place when needed.
+pub fn upgrade(store: DBStore, daemon: &Daemon) -> Result<DBStore> {
+    let migrations = pending_migrations(&store)?;
+    if migrations.is_empty() {
+        return Ok(store);
+    }
+    // Migrated rows must be persisted to the WAL, so disable bulk import mode.
+    let store = store.enable_compaction()?;
+    for migration in migrations {
+        info!(
+            "upgrading DB schema to version {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)(&store, daemon)
+            .chain_err(|| format!("DB migration to version {} failed", migration.version))?;
+        store.put_sync(version_row(migration.version));
+    }
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode;
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn};
+    use bitcoin::util::hash::Sha256dHash;
+
+    use super::{fix_truncated_spends, read_version, widen_txin_prev_index, TxInRowV1};
+    use fake::MemStore;
+    use index::{TxInRow, TxRow};
+    use store::{ReadStore, Row, WriteStore};
+    use util::{hash_prefix, HashPrefix};
+
+    fn spend(outputs: &[(Sha256dHash, u32)]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: outputs
+                .iter()
+                .map(|&(prev_hash, prev_index)| TxIn {
+                    prev_hash,
+                    prev_index,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![],
+        }
+    }
+
+    // As written by schema version 1 (without a version row).
+    fn v1_rows(txn: &Transaction) -> Vec<Row> {
+        let mut rows: Vec<Row> = txn
+            .input
+            .iter()
+            .map(|input| Row {
+                key: bincode::serialize(&TxInRowV1 {
+                    code: b'I',
+                    prev_hash_prefix: hash_prefix(&input.prev_hash[..]),
+                    prev_index: input.prev_index as u16,
+                    txid_prefix: hash_prefix(&txn.txid()[..]),
+                })
+                .unwrap(),
+                value: vec![],
+            })
+            .collect();
+        rows.push(TxRow::new(&txn.txid(), 1).to_row());
+        rows
+    }
+
+    #[test]
+    fn test_migrate_v1_txin_rows() {
+        let funding = Sha256dHash::from_data(b"funding");
+        let low = spend(&[(funding, 5)]);
+        let high = spend(&[(funding, 6 + 0x1_0000)]);
+        let both = spend(&[(funding, 7), (funding, 7 + 0x1_0000)]);
+
+        let store = MemStore::default();
+        for txn in &[&low, &high, &both] {
+            store.write(v1_rows(txn));
+        }
+        assert_eq!(read_version(&store).unwrap(), Some(1));
+
+        widen_txin_prev_index(&store).unwrap();
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: Sha256dHash::default(),
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![low.clone(), high.clone(), both.clone()],
+        };
+        fix_truncated_spends(&store, &block);
+
+        let spending = |index: usize| -> Vec<HashPrefix> {
+            store
+                .scan(&TxInRow::filter(&funding, index))
+                .iter()
+                .map(|row| TxInRow::from_row(row).txid_prefix)
+                .collect()
+        };
+        assert_eq!(spending(5), vec![hash_prefix(&low.txid()[..])]);
+        assert!(spending(6).is_empty());
+        assert_eq!(spending(6 + 0x1_0000), vec![hash_prefix(&high.txid()[..])]);
+        assert_eq!(spending(7), vec![hash_prefix(&both.txid()[..])]);
+        assert_eq!(spending(7 + 0x1_0000), vec![hash_prefix(&both.txid()[..])]);
+        assert_eq!(store.scan(b"I").len(), 4);
+        assert_eq!(read_version(&store).unwrap(), Some(1)); // set by `upgrade`
+    }
+}
@@ -1,68 +1,288 @@
-use bitcoin::network::constants::Network;
-use bitcoin::network::message::NetworkMessage;
-use bitcoin::network::message_blockdata::InvType;
-use bitcoin::network::socket::Socket;
+use bitcoin;
+use bitcoin::blockdata::block::Block;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::PROTOCOL_VERSION;
+use bitcoin::network::encodable::{ConsensusDecodable, ConsensusEncodable};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{InvType, Inventory};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::network::serialize::{BitcoinHash, RawDecoder, RawEncoder};
 use bitcoin::util::hash::Sha256dHash;
-use bitcoin::util::Error;
+use chan;
+use error_chain::ChainedError;
 
-use std::sync::mpsc::Sender;
+use std::cmp;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use daemon::Network;
+use errors::*;
+use signal::Waiter;
 use util;
 
-fn connect() -> Result<Socket, Error> {
-    let mut sock = Socket::new(Network::Bitcoin);
-    sock.connect("127.0.0.1", 8333)?;
-    Ok(sock)
+const USER_AGENT: &str = concat!("/electrs:", env!("CARGO_PKG_VERSION"), "/");
+
+// For connecting to the node, and for each read (unless changed by `set_read_timeout`).
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Wait before reconnecting to the node (doubling the delay after each failure).
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(3);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+// Events are dropped (instead of blocking the connection) if a subscriber falls behind.
+const SUBSCRIBER_QUEUE_SIZE: usize = 1000;
+
+// Wait for more transactions to be announced, before updating the mempool.
+const MEMPOOL_DELAY: Duration = Duration::from_secs(1);
+
+/// Inventory announced by the node.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Block(Sha256dHash),
+    Transaction(Sha256dHash),
 }
 
-fn handle(mut sock: Socket, tx: Sender<Sha256dHash>) {
-    let mut outgoing = vec![sock.version_message(0).unwrap()];
-    loop {
-        for msg in outgoing.split_off(0) {
-            trace!("send {:?}", msg);
-            if let Err(e) = sock.send_message(msg.clone()) {
-                warn!("failed to connect to node: {}", e);
-                break;
+/// P2P connection to the node, after a successful version handshake.
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+    magic: u32,
+    pings: u64, // nonce of the last `ping` sent
+}
+
+impl Connection {
+    /// Transactions' inventory is announced only if `relay` is set.
+    /// Reads time out after 30 seconds by default, so a stalled node fails the pending request.
+    pub fn connect(network: Network, addr: SocketAddr, relay: bool) -> Result<Connection> {
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+            .chain_err(|| ErrorKind::Connection(format!("failed to connect to {}", addr)))?;
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+            magic: network.magic(),
+            pings: 0,
+        };
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        conn.handshake(relay)?;
+        Ok(conn)
+    }
+
+    fn version_message(&self, relay: bool) -> Result<VersionMessage> {
+        let stream = self.stream.get_ref();
+        let receiver = stream.peer_addr().chain_err(|| "no peer address")?;
+        let sender = stream.local_addr().chain_err(|| "no local address")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Ok(VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: 0,
+            timestamp: now.as_secs() as i64,
+            receiver: Address::new(&receiver, 0),
+            sender: Address::new(&sender, 0),
+            nonce: now.as_secs() ^ u64::from(now.subsec_nanos()),
+            user_agent: USER_AGENT.to_owned(),
+            start_height: 0,
+            relay,
+        })
+    }
+
+    fn handshake(&mut self, relay: bool) -> Result<()> {
+        let version = self.version_message(relay)?;
+        self.send(NetworkMessage::Version(version))?;
+        let (mut got_version, mut got_verack) = (false, false);
+        while !(got_version && got_verack) {
+            match self.recv()? {
+                NetworkMessage::Version(msg) => {
+                    info!(
+                        "connected to {:?} (protocol {}, height {})",
+                        msg.user_agent, msg.version, msg.start_height
+                    );
+                    got_version = true;
+                    self.send(NetworkMessage::Verack)?;
+                }
+                NetworkMessage::Verack => got_verack = true,
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                msg => debug!("ignoring {:?} during handshake", msg),
             }
         }
-        // Receive new message
-        let msg = match sock.receive_message() {
-            Ok(msg) => msg,
-            Err(e) => {
-                warn!("failed to receive p2p message: {}", e);
-                break;
+        Ok(())
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream
+            .get_ref()
+            .set_read_timeout(timeout)
+            .chain_err(|| "failed to set read timeout")
+    }
+
+    /// Returns the blocks in the requested order (including their witness data).
+    /// Fails if any of them is missing, since bitcoind replies to the following `ping` only
+    /// after sending the requested blocks (it doesn't reply with `notfound` for missing ones).
+    pub fn getblocks(&mut self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        let inventory = blockhashes
+            .iter()
+            .map(|hash| Inventory {
+                inv_type: InvType::WitnessBlock,
+                hash: *hash,
+            })
+            .collect();
+        self.send(NetworkMessage::GetData(inventory))?;
+        self.pings += 1;
+        let nonce = self.pings;
+        self.send(NetworkMessage::Ping(nonce))?;
+        let mut blocks = Vec::with_capacity(blockhashes.len());
+        while blocks.len() < blockhashes.len() {
+            match self.recv()? {
+                NetworkMessage::Block(block) => {
+                    let expected = blockhashes[blocks.len()];
+                    if block.bitcoin_hash() != expected {
+                        bail!(
+                            "unexpected block {} (expected {})",
+                            block.bitcoin_hash(),
+                            expected
+                        );
+                    }
+                    blocks.push(block);
+                }
+                NetworkMessage::Pong(n) if n == nonce => {
+                    bail!("{} blocks not found", blockhashes.len() - blocks.len())
+                }
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                _ => (),
             }
+        }
+        Ok(blocks)
+    }
+
+    pub fn send(&mut self, msg: NetworkMessage) -> Result<()> {
+        trace!("send {:?}", msg);
+        let stream = self.stream.get_mut();
+        let raw = RawNetworkMessage {
+            magic: self.magic,
+            payload: msg,
         };
-        trace!("recv {:?}", msg);
-        match msg {
-            NetworkMessage::Alert(_) => continue, // deprecated
-            NetworkMessage::Version(_) => outgoing.push(NetworkMessage::Verack),
-            NetworkMessage::Ping(nonce) => outgoing.push(NetworkMessage::Pong(nonce)),
-            NetworkMessage::Inv(ref inventory) => {
-                inventory
-                    .iter()
-                    .filter(|inv| inv.inv_type == InvType::Block)
-                    .for_each(|inv| tx.send(inv.hash).expect("failed to send message"));
+        raw.consensus_encode(&mut RawEncoder::new(&mut *stream))
+            .chain_err(|| "failed to send p2p message")?;
+        stream.flush().chain_err(|| "failed to send p2p message")
+    }
+
+    /// Skips messages that cannot be parsed (e.g. unsupported commands), except for blocks.
+    pub fn recv(&mut self) -> Result<NetworkMessage> {
+        loop {
+            let result: ::std::result::Result<RawNetworkMessage, bitcoin::util::Error> =
+                ConsensusDecodable::consensus_decode(&mut RawDecoder::new(&mut self.stream));
+            let raw = match result {
+                Ok(raw) => raw,
+                // the whole payload has been read, so the stream is still in sync
+                Err(bitcoin::util::Error::Detail(ref msg, _)) if msg != "block" => {
+                    debug!("skipping p2p message: {}", msg);
+                    continue;
+                }
+                Err(e) => return Err(e).chain_err(|| "failed to receive p2p message"),
+            };
+            if raw.magic != self.magic {
+                bail!(
+                    "unexpected magic {:x} (expected {:x})",
+                    raw.magic,
+                    self.magic
+                );
             }
-            _ => (),
-        };
+            trace!("recv {:?}", raw.payload);
+            return Ok(raw.payload);
+        }
     }
 }
 
-pub fn run() -> util::Channel<Sha256dHash> {
-    let chan = util::Channel::new();
-    let tx = chan.sender();
+/// Publishes the inventory announced by the node to its subscribers.
+#[derive(Clone)]
+pub struct Notifier {
+    subscribers: Arc<Mutex<Vec<chan::Sender<Event>>>>,
+}
 
-    util::spawn_thread("p2p", move || loop {
-        // TODO: support testnet and regtest as well.
-        match connect() {
-            Ok(sock) => handle(sock, tx.clone()),
-            Err(e) => warn!("p2p error: {}", e),
+impl Notifier {
+    pub fn start(network: Network, addr: SocketAddr) -> Notifier {
+        let notifier = Notifier {
+            subscribers: Arc::new(Mutex::new(vec![])),
+        };
+        let publisher = notifier.clone();
+        util::spawn_thread("p2p", move || {
+            let mut delay = MIN_RECONNECT_DELAY;
+            let mut failures = 0;
+            loop {
+                let result = match Connection::connect(network, addr, /*relay=*/ true) {
+                    Ok(conn) => {
+                        delay = MIN_RECONNECT_DELAY;
+                        failures = 0;
+                        publisher.handle(conn)
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    failures += 1;
+                    if failures == 1 {
+                        warn!("p2p error: {}", e.display_chain());
+                    } else {
+                        debug!("p2p error ({} failures): {}", failures, e.display_chain());
+                    }
+                }
+                thread::sleep(delay);
+                delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+            }
+        });
+        notifier
+    }
+
+    pub fn subscribe(&self) -> chan::Receiver<Event> {
+        let (tx, rx) = chan::sync(SUBSCRIBER_QUEUE_SIZE);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn publish(&self, event: Event) {
+        for tx in self.subscribers.lock().unwrap().iter() {
+            chan_select! {
+                default => trace!("subscriber is full, dropping {:?}", event),
+                tx.send(event.clone()) => {},
+            }
         }
-        thread::sleep(Duration::from_secs(3));
-    });
+    }
 
-    chan
+    fn handle(&self, mut conn: Connection) -> Result<()> {
+        conn.set_read_timeout(None)?; // announcements may be infrequent
+        loop {
+            match conn.recv()? {
+                NetworkMessage::Ping(nonce) => conn.send(NetworkMessage::Pong(nonce))?,
+                NetworkMessage::Inv(inventory) => {
+                    for inv in inventory {
+                        self.publish(match inv.inv_type {
+                            InvType::Block | InvType::WitnessBlock => Event::Block(inv.hash),
+                            InvType::Transaction | InvType::WitnessTransaction => {
+                                Event::Transaction(inv.hash)
+                            }
+                            InvType::Error => continue,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Returns when a new block is announced, shortly after new transactions are announced,
+/// or after the timeout.
+pub fn wait(events: &chan::Receiver<Event>, signal: &Waiter, timeout: Duration) -> Result<()> {
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        match signal.wait_for(deadline - now, events)? {
+            Some(Event::Block(_)) | None => return Ok(()),
+            Some(Event::Transaction(_)) => {
+                deadline = cmp::min(deadline, Instant::now() + MEMPOOL_DELAY)
+            }
+        }
+    }
 }
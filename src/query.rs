@@ -1,19 +1,21 @@
-use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::network::serialize::deserialize;
+use bitcoin::network::serialize::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-
-use app::App;
-use index::{compute_script_hash, TxInRow, TxOutRow, TxRow};
+use lru::LruCache;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use app::{App, Snapshot};
+use index::{compute_script_hash, BlockTxidsRow, Touched, TxInRow, TxOutRow, TxRow};
 use mempool::Tracker;
-use metrics::Metrics;
+use metrics::{Counter, CounterVec, Gauge, MetricOpts, Metrics};
 use serde_json::Value;
 use store::{ReadStore, Row};
-use util::{FullHash, HashPrefix, HeaderEntry};
+use util::{create_merkle_branch_and_root, full_hash, Bytes, FullHash, HashPrefix, HeaderEntry};
 
 use errors::*;
 
@@ -36,6 +38,9 @@ struct SpendingInput {
 pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
+    unconfirmed_inputs: HashSet<Sha256dHash>, // mempool txids spending unconfirmed outputs
+    history: Vec<(i32, Sha256dHash)>,         // computed once, since statuses are cached
+    hash: Option<FullHash>,                   // computed once, since statuses are cached
 }
 
 fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
@@ -61,7 +66,11 @@ impl Status {
         calc_balance(&self.mempool)
     }
 
-    pub fn history(&self) -> Vec<(i32, Sha256dHash)> {
+    pub fn history(&self) -> &[(i32, Sha256dHash)] {
+        &self.history
+    }
+
+    fn compute_history(&self) -> Vec<(i32, Sha256dHash)> {
         let mut txns_map = HashMap::<Sha256dHash, i32>::new();
         for f in self.funding() {
             txns_map.insert(f.txn_id, f.height as i32);
@@ -69,12 +78,29 @@ impl Status {
         for s in self.spending() {
             txns_map.insert(s.txn_id, s.height as i32);
         }
+        for txn_id in &self.unconfirmed_inputs {
+            txns_map.insert(*txn_id, -1);
+        }
         let mut txns: Vec<(i32, Sha256dHash)> =
             txns_map.into_iter().map(|item| (item.1, item.0)).collect();
-        txns.sort_unstable();
+        // confirmed transactions (by height), followed by the mempool ones (height 0, then -1)
+        let mempool_txids = self.mempool_txids();
+        txns.sort_unstable_by_key(|&(height, txn_id)| {
+            if mempool_txids.contains(&txn_id) {
+                (true, -height, txn_id)
+            } else {
+                (false, height, txn_id)
+            }
+        });
         txns
     }
 
+    pub fn mempool_txids(&self) -> HashSet<Sha256dHash> {
+        let funding = self.mempool.0.iter().map(|f| f.txn_id);
+        let spending = self.mempool.1.iter().map(|s| s.txn_id);
+        funding.chain(spending).collect()
+    }
+
     pub fn unspent(&self) -> Vec<&FundingOutput> {
         let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
         for f in self.funding() {
@@ -94,6 +120,10 @@ impl Status {
     }
 
     pub fn hash(&self) -> Option<FullHash> {
+        self.hash
+    }
+
+    fn compute_hash(&self) -> Option<FullHash> {
         let txns = self.history();
         if txns.is_empty() {
             None
@@ -115,11 +145,6 @@ struct TxnHeight {
     height: u32,
 }
 
-fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
-    let data = [&left[..], &right[..]].concat();
-    Sha256dHash::from_data(&data)
-}
-
 // TODO: the functions below can be part of ReadStore.
 fn txrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<TxRow> {
     let key = TxRow::filter_full(&txid);
@@ -127,85 +152,321 @@ fn txrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<TxRow> {
     Some(TxRow::from_row(&Row { key, value }))
 }
 
-fn txrows_by_prefix(store: &ReadStore, txid_prefix: &HashPrefix) -> Vec<TxRow> {
+fn txrows_by_prefix<'a>(
+    store: &'a ReadStore,
+    txid_prefix: &HashPrefix,
+) -> impl Iterator<Item = TxRow> + 'a {
     store
-        .scan(&TxRow::filter_prefix(&txid_prefix))
-        .iter()
-        .map(|row| TxRow::from_row(row))
-        .collect()
+        .iter_scan(&TxRow::filter_prefix(&txid_prefix))
+        .map(|row| TxRow::from_row(&row))
 }
 
-fn txids_by_script_hash(store: &ReadStore, script_hash: &[u8]) -> Vec<HashPrefix> {
+fn txids_by_script_hash<'a>(
+    store: &'a ReadStore,
+    script_hash: &[u8],
+) -> impl Iterator<Item = HashPrefix> + 'a {
     store
-        .scan(&TxOutRow::filter(script_hash))
-        .iter()
-        .map(|row| TxOutRow::from_row(row).txid_prefix)
-        .collect()
+        .iter_scan(&TxOutRow::filter(script_hash))
+        .map(|row| TxOutRow::from_row(&row).txid_prefix)
 }
 
-fn txids_by_funding_output(
-    store: &ReadStore,
+// Stops scanning after `limit` items (0 = unlimited), failing if there are more of them.
+fn collect_limited<I: Iterator>(iter: I, limit: usize, what: &str) -> Result<Vec<I::Item>> {
+    if limit == 0 {
+        return Ok(iter.collect());
+    }
+    let items: Vec<I::Item> = iter.take(limit + 1).collect();
+    if items.len() > limit {
+        bail!(ErrorKind::LimitExceeded(
+            "history_size",
+            format!("more than {} {} found for this script hash", limit, what)
+        ));
+    }
+    Ok(items)
+}
+
+fn txids_by_funding_output<'a>(
+    store: &'a ReadStore,
     txn_id: &Sha256dHash,
     output_index: usize,
-) -> Vec<HashPrefix> {
+) -> impl Iterator<Item = HashPrefix> + 'a {
     store
-        .scan(&TxInRow::filter(&txn_id, output_index))
-        .iter()
-        .map(|row| TxInRow::from_row(row).txid_prefix)
-        .collect()
+        .iter_scan(&TxInRow::filter(&txn_id, output_index))
+        .map(|row| TxInRow::from_row(&row).txid_prefix)
+}
+
+struct CachedTxn {
+    serialized: Bytes, // more compact than a parsed Transaction
+    height: u32,       // of the block it was loaded from (0 for mempool transactions)
+}
+
+struct TransactionLru {
+    map: LruCache<Sha256dHash, CachedTxn>,
+    size: usize, // total serialized size of the cached transactions
+}
+
+impl TransactionLru {
+    fn remove(&mut self, txid: &Sha256dHash) -> bool {
+        match self.map.pop(txid) {
+            Some(cached) => {
+                self.size -= cached.serialized.len();
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 struct TransactionCache {
-    map: RwLock<HashMap<Sha256dHash, Transaction>>,
+    lru: Mutex<TransactionLru>,
+    capacity: usize, // maximal total size (in bytes) of the cached transactions
+    lookups: CounterVec,
+    evictions: CounterVec,
+    size: Gauge,
 }
 
 impl TransactionCache {
-    fn new() -> TransactionCache {
+    fn new(capacity: usize, metrics: &Metrics) -> TransactionCache {
         TransactionCache {
-            map: RwLock::new(HashMap::new()),
+            lru: Mutex::new(TransactionLru {
+                map: LruCache::unbounded(), // bounded by `capacity` instead of entries' count
+                size: 0,
+            }),
+            capacity,
+            lookups: metrics.counter_vec(
+                MetricOpts::new("tx_cache_lookups", "# of transaction cache lookups"),
+                &["result"],
+            ),
+            evictions: metrics.counter_vec(
+                MetricOpts::new("tx_cache_evictions", "# of transactions evicted from cache"),
+                &["reason"],
+            ),
+            size: metrics.gauge(MetricOpts::new(
+                "tx_cache_size",
+                "Total size of cached transactions (in bytes)",
+            )),
         }
     }
 
-    fn get_or_else<F>(&self, txid: &Sha256dHash, load_txn_func: F) -> Result<Transaction>
+    fn get_or_else<F>(
+        &self,
+        txid: &Sha256dHash,
+        height: u32,
+        load_txn_func: F,
+    ) -> Result<Transaction>
     where
         F: FnOnce() -> Result<Transaction>,
     {
-        if let Some(txn) = self.map.read().unwrap().get(txid) {
-            return Ok(txn.clone());
+        if let Some(serialized) = self.get(txid, height) {
+            self.lookups.with_label_values(&["hit"]).inc();
+            return deserialize(&serialized).chain_err(|| format!("invalid cached tx {}", txid));
         }
+        self.lookups.with_label_values(&["miss"]).inc();
         let txn = load_txn_func()?;
-        self.map.write().unwrap().insert(*txid, txn.clone());
+        self.put(txid, height, serialize(&txn).unwrap());
         Ok(txn)
     }
+
+    fn get(&self, txid: &Sha256dHash, height: u32) -> Option<Bytes> {
+        let mut lru = self.lru.lock().unwrap();
+        match lru.map.get(txid) {
+            Some(cached) if cached.height == height => return Some(cached.serialized.clone()),
+            Some(_) => (), // the transaction was confirmed, or moved to another block by a reorg
+            None => return None,
+        }
+        lru.remove(txid);
+        self.evictions.with_label_values(&["stale"]).inc();
+        self.size.set(lru.size as i64);
+        None
+    }
+
+    fn put(&self, txid: &Sha256dHash, height: u32, serialized: Bytes) {
+        if serialized.len() > self.capacity {
+            return; // also when the cache is disabled
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(txid); // may have been loaded concurrently
+        while lru.size + serialized.len() > self.capacity {
+            let (_, evicted) = lru.map.pop_lru().expect("empty cache is too large");
+            lru.size -= evicted.serialized.len();
+            self.evictions.with_label_values(&["size"]).inc();
+        }
+        lru.size += serialized.len();
+        lru.map.put(*txid, CachedTxn { serialized, height });
+        self.size.set(lru.size as i64);
+    }
+}
+
+struct StatusLru {
+    map: LruCache<FullHash, Arc<Status>>,
+    generation: usize,          // incremented when cached statuses may become stale
+    pending: HashSet<FullHash>, // statuses being computed (by a single caller each)
+}
+
+// Shared by all connections, so each status is recomputed only after it's touched.
+struct StatusCache {
+    lru: Mutex<StatusLru>,
+    computed: Condvar, // notified when a pending status is done
+    capacity: usize,   // maximal number of cached statuses
+    lookups: CounterVec,
+    evictions: Counter,
+}
+
+impl StatusCache {
+    fn new(capacity: usize, metrics: &Metrics) -> StatusCache {
+        StatusCache {
+            lru: Mutex::new(StatusLru {
+                map: LruCache::new(cmp::max(capacity, 1)),
+                generation: 0,
+                pending: HashSet::new(),
+            }),
+            computed: Condvar::new(),
+            capacity,
+            lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "status_cache_lookups",
+                    "# of script hash status cache lookups",
+                ),
+                &["result"],
+            ),
+            evictions: metrics.counter(MetricOpts::new(
+                "status_cache_evictions",
+                "# of script hash statuses evicted from cache after being touched",
+            )),
+        }
+    }
+
+    // Waits for another caller computing the same status, so it is computed only once.
+    fn get<'a>(&'a self, script_hash: &FullHash) -> CachedStatus<'a> {
+        let mut lru = self.lru.lock().unwrap();
+        let mut waited = false;
+        loop {
+            if let Some(status) = lru.map.get(script_hash).cloned() {
+                let result = if waited { "wait" } else { "hit" };
+                self.lookups.with_label_values(&[result]).inc();
+                return CachedStatus::Hit(status);
+            }
+            // a failed (or stale) computation is retried by one of its waiters
+            if self.capacity == 0 || !lru.pending.contains(script_hash) {
+                break;
+            }
+            waited = true;
+            lru = self.computed.wait(lru).unwrap();
+        }
+        self.lookups.with_label_values(&["miss"]).inc();
+        if self.capacity > 0 {
+            lru.pending.insert(*script_hash);
+        }
+        CachedStatus::Miss(PendingStatus {
+            cache: self,
+            script_hash: *script_hash,
+            generation: lru.generation,
+            status: None,
+        })
+    }
+
+    fn done(&self, script_hash: &FullHash, status: Option<Arc<Status>>, generation: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        // the status may be computed from an older state (before the last invalidation)
+        if let Some(status) = status {
+            if lru.generation == generation {
+                lru.map.put(*script_hash, status);
+            }
+        }
+        lru.pending.remove(script_hash);
+        self.computed.notify_all();
+    }
+
+    fn invalidate(&self, touched: &Touched) {
+        if touched.is_empty() {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.generation += 1;
+        let stale: Vec<FullHash> = lru
+            .map
+            .iter()
+            .filter(|(script_hash, status)| {
+                touched.all
+                    || touched.script_hashes.contains(*script_hash)
+                    || status
+                        .funding()
+                        .any(|f| touched.outpoints.contains(&(f.txn_id, f.output_index)))
+            })
+            .map(|(script_hash, _)| *script_hash)
+            .collect();
+        for script_hash in &stale {
+            lru.map.pop(script_hash);
+        }
+        self.evictions.inc_by(stale.len() as i64);
+    }
+}
+
+enum CachedStatus<'a> {
+    Hit(Arc<Status>),
+    Miss(PendingStatus<'a>), // the status should be computed by the caller
+}
+
+// Marks a status as being computed, until it is cached (or dropped on failure).
+struct PendingStatus<'a> {
+    cache: &'a StatusCache,
+    script_hash: FullHash,
+    generation: usize, // of the cache, when the status started being computed
+    status: Option<Arc<Status>>,
+}
+
+impl<'a> PendingStatus<'a> {
+    fn put(mut self, status: Arc<Status>) {
+        self.status = Some(status); // cached when dropped
+    }
+}
+
+impl<'a> Drop for PendingStatus<'a> {
+    fn drop(&mut self) {
+        self.cache
+            .done(&self.script_hash, self.status.take(), self.generation);
+    }
 }
 
 pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
     tx_cache: TransactionCache,
+    status_cache: StatusCache,
+    max_history_size: usize, // 0 = unlimited
 }
 
 impl Query {
-    pub fn new(app: Arc<App>, metrics: &Metrics) -> Arc<Query> {
+    pub fn new(
+        app: Arc<App>,
+        metrics: &Metrics,
+        tx_cache_size: usize,
+        status_cache_size: usize,
+        max_history_size: usize,
+    ) -> Arc<Query> {
         Arc::new(Query {
             app,
             tracker: RwLock::new(Tracker::new(metrics)),
-            tx_cache: TransactionCache::new(),
+            tx_cache: TransactionCache::new(tx_cache_size, metrics),
+            status_cache: StatusCache::new(status_cache_size, metrics),
+            max_history_size,
         })
     }
 
-    fn load_txns_by_prefix(
-        &self,
-        store: &ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<TxnHeight>> {
+    fn load_txns_by_prefix<I>(&self, store: &ReadStore, prefixes: I) -> Result<Vec<TxnHeight>>
+    where
+        I: IntoIterator<Item = HashPrefix>,
+    {
         let mut txns = vec![];
         for txid_prefix in prefixes {
             for tx_row in txrows_by_prefix(store, &txid_prefix) {
                 let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
                 let txn = self
                     .tx_cache
-                    .get_or_else(&txid, || self.load_txn(&txid, tx_row.height))?;
+                    .get_or_else(&txid, tx_row.height, || self.load_txn(&txid, tx_row.height))?;
                 txns.push(TxnHeight {
                     txn,
                     height: tx_row.height,
@@ -220,31 +481,25 @@ impl Query {
         store: &ReadStore,
         funding: &FundingOutput,
     ) -> Result<Option<SpendingInput>> {
-        let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
-            store,
-            txids_by_funding_output(store, &funding.txn_id, funding.output_index),
-        )?;
-        let mut spending_inputs = vec![];
-        for t in &spending_txns {
-            for input in t.txn.input.iter() {
-                if input.prev_hash == funding.txn_id
-                    && input.prev_index == funding.output_index as u32
-                {
-                    spending_inputs.push(SpendingInput {
-                        txn_id: t.txn.txid(),
-                        height: t.height,
-                        funding_output: (funding.txn_id, funding.output_index),
-                        value: funding.value,
-                    })
+        let txid_prefixes = txids_by_funding_output(store, &funding.txn_id, funding.output_index);
+        for txid_prefix in txid_prefixes {
+            // An output can be spent at most once, so stop at the first spending input.
+            for t in self.load_txns_by_prefix(store, iter::once(txid_prefix))? {
+                for input in t.txn.input.iter() {
+                    if input.prev_hash == funding.txn_id
+                        && input.prev_index == funding.output_index as u32
+                    {
+                        return Ok(Some(SpendingInput {
+                            txn_id: t.txn.txid(),
+                            height: t.height,
+                            funding_output: (funding.txn_id, funding.output_index),
+                            value: funding.value,
+                        }));
+                    }
                 }
             }
         }
-        assert!(spending_inputs.len() <= 1);
-        Ok(if spending_inputs.len() == 1 {
-            Some(spending_inputs.remove(0))
-        } else {
-            None
-        })
+        Ok(None)
     }
 
     fn find_funding_outputs(&self, t: &TxnHeight, script_hash: &[u8]) -> Vec<FundingOutput> {
@@ -263,20 +518,28 @@ impl Query {
         result
     }
 
+    // Fails (before loading any transaction) if the history is too large.
+    fn history_prefixes(&self, store: &ReadStore, script_hash: &[u8]) -> Result<Vec<HashPrefix>> {
+        collect_limited(
+            txids_by_script_hash(store, script_hash),
+            self.max_history_size,
+            "transactions",
+        )
+    }
+
     fn confirmed_status(
         &self,
+        read_store: &ReadStore,
         script_hash: &[u8],
+        txid_prefixes: Vec<HashPrefix>,
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
         let mut funding = vec![];
         let mut spending = vec![];
-        for t in self.load_txns_by_prefix(
-            self.app.read_store(),
-            txids_by_script_hash(self.app.read_store(), script_hash),
-        )? {
+        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(self.app.read_store(), &funding_output)? {
+            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
                 spending.push(spent);
             }
         }
@@ -291,10 +554,8 @@ impl Query {
         let mut funding = vec![];
         let mut spending = vec![];
         let tracker = self.tracker.read().unwrap();
-        for t in self.load_txns_by_prefix(
-            tracker.index(),
-            txids_by_script_hash(tracker.index(), script_hash),
-        )? {
+        let txid_prefixes = self.history_prefixes(tracker.index(), script_hash)?;
+        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
@@ -306,14 +567,62 @@ impl Query {
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+    pub fn status(&self, script_hash: &[u8]) -> Result<Arc<Status>> {
+        let key = full_hash(script_hash);
+        let pending = match self.status_cache.get(&key) {
+            CachedStatus::Hit(status) => return Ok(status),
+            CachedStatus::Miss(pending) => pending,
+        };
+        let status = Arc::new(self.compute_status(script_hash)?);
+        pending.put(status.clone());
+        Ok(status)
+    }
+
+    fn compute_status(&self, script_hash: &[u8]) -> Result<Status> {
+        let snapshot = self.app.snapshot();
+        let txid_prefixes = self.history_prefixes(snapshot.store(), script_hash)?;
         let confirmed = self
-            .confirmed_status(script_hash)
+            .confirmed_status(snapshot.store(), script_hash, txid_prefixes)
             .chain_err(|| "failed to get confirmed status")?;
-        let mempool = self
-            .mempool_status(script_hash, &confirmed.0)
-            .chain_err(|| "failed to get mempool status")?;
-        Ok(Status { confirmed, mempool })
+        // not chained, so a LimitExceeded error is reported as such to the client
+        let mempool = self.mempool_status(script_hash, &confirmed.0)?;
+        let mut status = Status {
+            confirmed,
+            mempool,
+            unconfirmed_inputs: HashSet::new(),
+            history: vec![],
+            hash: None,
+        };
+        let tracker = self.tracker.read().unwrap();
+        status.unconfirmed_inputs = status
+            .mempool_txids()
+            .into_iter()
+            .filter(|txn_id| tracker.has_unconfirmed_inputs(txn_id))
+            .collect();
+        status.history = status.compute_history();
+        status.hash = status.compute_hash();
+        Ok(status)
+    }
+
+    /// Returns (height, txid, fee) of the status' unconfirmed transactions, where the height is
+    /// -1 for transactions with unconfirmed inputs (and 0 otherwise).
+    pub fn get_mempool(&self, status: &Status) -> Vec<(i32, Sha256dHash, u64)> {
+        let tracker = self.tracker.read().unwrap();
+        let mut txns: Vec<(i32, Sha256dHash, u64)> = status
+            .mempool_txids()
+            .into_iter()
+            .filter_map(|txid| {
+                let fee = tracker.get_fee(&txid)?; // may be removed since loading the status
+                let height = if status.unconfirmed_inputs.contains(&txid) {
+                    -1
+                } else {
+                    0
+                };
+                Some((height, txid, fee))
+            })
+            .collect();
+        txns.sort_unstable_by_key(|&(height, txid, _)| (-height, txid)); // as in `Status::history`
+        txns
     }
 
     fn lookup_confirmed_blockhash(
@@ -325,17 +634,16 @@ impl Query {
             None // found in mempool (as unconfirmed transaction)
         } else {
             // Lookup in confirmed transactions' index
+            let snapshot = self.app.snapshot();
             let height = match block_height {
                 Some(height) => height,
                 None => {
-                    txrow_by_txid(self.app.read_store(), &tx_hash)
+                    txrow_by_txid(snapshot.store(), &tx_hash)
                         .chain_err(|| format!("not indexed tx {}", tx_hash))?
                         .height
                 }
             };
-            let header = self
-                .app
-                .index()
+            let header = snapshot
                 .get_header(height as usize)
                 .chain_err(|| format!("missing header at height {}", height))?;
             Some(*header.hash())
@@ -357,59 +665,143 @@ impl Query {
             .gettransaction_raw(tx_hash, blockhash, verbose)
     }
 
+    /// Returns the transaction and its confirming block's header (`None` if unconfirmed),
+    /// or `None` if the transaction is unknown.
+    pub fn get_transaction_with_header(
+        &self,
+        tx_hash: &Sha256dHash,
+    ) -> Result<Option<(Transaction, Option<HeaderEntry>)>> {
+        if let Some(txn) = self.tracker.read().unwrap().get_txn(tx_hash) {
+            return Ok(Some((txn, None)));
+        }
+        let snapshot = self.app.snapshot();
+        let height = match txrow_by_txid(snapshot.store(), tx_hash) {
+            Some(row) => row.height,
+            None => return Ok(None),
+        };
+        let header = snapshot
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        let txn = self
+            .tx_cache
+            .get_or_else(tx_hash, height, || self.load_txn(tx_hash, height))?;
+        Ok(Some((txn, Some(header))))
+    }
+
     pub fn get_headers(&self, heights: &[usize]) -> Vec<HeaderEntry> {
-        let index = self.app.index();
+        let snapshot = self.app.snapshot();
         heights
             .iter()
-            .filter_map(|height| index.get_header(*height))
+            .filter_map(|height| snapshot.get_header(*height))
             .collect()
     }
 
+    pub fn get_header_by_hash(&self, blockhash: &Sha256dHash) -> Option<HeaderEntry> {
+        self.app.snapshot().get_header_by_hash(blockhash)
+    }
+
+    /// Returns the merkle branch of the header at `height`, and the merkle root
+    /// of all the headers up to the checkpoint at `cp_height`.
+    pub fn get_header_merkle_proof(
+        &self,
+        height: usize,
+        cp_height: usize,
+    ) -> Result<(Vec<Sha256dHash>, Sha256dHash)> {
+        if height > cp_height {
+            bail!(
+                "header height {} must be <= cp_height {}",
+                height,
+                cp_height
+            );
+        }
+        self.app
+            .snapshot()
+            .get_header_proof(height, cp_height)
+            .chain_err(|| format!("cp_height {} is beyond the best header", cp_height))
+    }
+
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
-        let last_header = self.app.index().best_header();
+        let last_header = self.app.snapshot().best_header();
         Ok(last_header.chain_err(|| "no headers indexed")?.clone())
     }
 
+    /// Returns the transaction IDs of the block, in their original order.
+    pub fn get_block_txids(&self, blockhash: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
+        self.block_txids(&*self.app.snapshot(), blockhash)
+    }
+
+    fn block_txids(
+        &self,
+        snapshot: &Snapshot,
+        blockhash: &Sha256dHash,
+    ) -> Result<Vec<Sha256dHash>> {
+        let key = BlockTxidsRow::filter(blockhash);
+        if let Some(value) = snapshot.store().get(&key) {
+            let row = BlockTxidsRow::from_row(&Row { key, value });
+            return Ok(row
+                .txids
+                .iter()
+                .map(|txid| deserialize(txid).unwrap())
+                .collect());
+        }
+        // Blocks indexed by older versions don't have their transaction IDs persisted.
+        let block = self.app.daemon().getblock(blockhash)?;
+        Ok(block.txdata.iter().map(|tx| tx.txid()).collect())
+    }
+
     pub fn get_merkle_proof(
         &self,
         tx_hash: &Sha256dHash,
         height: usize,
     ) -> Result<(Vec<Sha256dHash>, usize)> {
-        let header_entry = self
-            .app
-            .index()
+        let snapshot = self.app.snapshot();
+        let header_entry = snapshot
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
-        let block: Block = self.app.daemon().getblock(&header_entry.hash())?;
-        let mut txids: Vec<Sha256dHash> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let txids = self.block_txids(&snapshot, header_entry.hash())?;
         let pos = txids
             .iter()
             .position(|txid| txid == tx_hash)
             .chain_err(|| format!("missing txid {}", tx_hash))?;
-        let mut merkle = vec![];
-        let mut index = pos;
-        while txids.len() > 1 {
-            if txids.len() % 2 != 0 {
-                let last = txids.last().unwrap().clone();
-                txids.push(last);
-            }
-            index = if index % 2 == 0 { index + 1 } else { index - 1 };
-            merkle.push(txids[index]);
-            index = index / 2;
-            txids = txids
-                .chunks(2)
-                .map(|pair| merklize(pair[0], pair[1]))
-                .collect()
-        }
+        let (merkle, _root) = create_merkle_branch_and_root(txids, pos);
         Ok((merkle, pos))
     }
 
+    pub fn get_id_from_pos(
+        &self,
+        height: usize,
+        tx_pos: usize,
+        want_merkle: bool,
+    ) -> Result<(Sha256dHash, Vec<Sha256dHash>)> {
+        let snapshot = self.app.snapshot();
+        let header_entry = snapshot
+            .get_header(height)
+            .chain_err(|| format!("missing block #{}", height))?;
+        let txids = self.block_txids(&snapshot, header_entry.hash())?;
+        let txid = *txids
+            .get(tx_pos)
+            .chain_err(|| format!("no tx in position {} in block #{}", tx_pos, height))?;
+        let branch = if want_merkle {
+            create_merkle_branch_and_root(txids, tx_pos).0
+        } else {
+            vec![]
+        };
+        Ok((txid, branch))
+    }
+
     pub fn broadcast(&self, txn: &Transaction) -> Result<Sha256dHash> {
         self.app.daemon().broadcast(txn)
     }
 
     pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+        let touched = self.tracker.write().unwrap().update(self.app.daemon())?;
+        self.status_cache.invalidate(&touched);
+        Ok(())
+    }
+
+    /// Should be called after new blocks are indexed (see `App::update`).
+    pub fn invalidate_statuses(&self, touched: &Touched) {
+        self.status_cache.invalidate(touched);
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -432,3 +824,142 @@ impl Query {
         last_fee_rate * 1e-5 // [BTC/kB] = 10^5 [sat/B]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::util::hash::Sha256dHash;
+    use hex;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{CachedStatus, FundingOutput, PendingStatus, SpendingInput, Status, StatusCache};
+    use index::Touched;
+    use metrics::Metrics;
+    use util::FullHash;
+
+    fn txid(name: &str) -> Sha256dHash {
+        Sha256dHash::from_data(name.as_bytes())
+    }
+
+    fn funding(name: &str, height: u32) -> FundingOutput {
+        FundingOutput {
+            txn_id: txid(name),
+            height,
+            output_index: 0,
+            value: 100,
+        }
+    }
+
+    fn spending(name: &str, height: u32, funding_name: &str) -> SpendingInput {
+        SpendingInput {
+            txn_id: txid(name),
+            height,
+            funding_output: (txid(funding_name), 0),
+            value: 100,
+        }
+    }
+
+    // Confirmed: "a" (height 5) and "b" (height 2), spent by "c" (height 7).
+    // Mempool: "d" spends "a", and is the parent of "e", which is the parent of "f".
+    fn mempool_chain_status() -> Status {
+        let mut status = Status {
+            confirmed: (
+                vec![funding("a", 5), funding("b", 2)],
+                vec![spending("c", 7, "b")],
+            ),
+            mempool: (
+                vec![funding("d", 0), funding("e", 0), funding("f", 0)],
+                vec![
+                    spending("d", 0, "a"),
+                    spending("e", 0, "d"),
+                    spending("f", 0, "e"),
+                ],
+            ),
+            unconfirmed_inputs: vec![txid("e"), txid("f")].into_iter().collect(),
+            history: vec![],
+            hash: None,
+        };
+        status.history = status.compute_history();
+        status.hash = status.compute_hash();
+        status
+    }
+
+    #[test]
+    fn test_mempool_chain_history() {
+        let status = mempool_chain_status();
+        let (e, f) = if txid("e") < txid("f") {
+            (txid("e"), txid("f"))
+        } else {
+            (txid("f"), txid("e"))
+        };
+        assert_eq!(
+            status.history(),
+            &[
+                (2, txid("b")),
+                (5, txid("a")),
+                (7, txid("c")),
+                (0, txid("d")),
+                (-1, e),
+                (-1, f),
+            ][..]
+        );
+        assert_eq!(status.confirmed_balance(), 100);
+        assert_eq!(status.mempool_balance(), 0);
+        assert_eq!(status.unspent().len(), 1);
+    }
+
+    #[test]
+    fn test_mempool_chain_hash() {
+        // sha256("<b>:2:<a>:5:<c>:7:<d>:0:<f>:-1:<e>:-1:") of the big-endian hex txids
+        let status = mempool_chain_status();
+        assert_eq!(
+            status.hash().map(hex::encode),
+            Some("70ac7bc042aa07fb9a79cecef5ed6267dfb5c4a2ad2eb7c5002c47fa0369b160".to_owned())
+        );
+
+        // a transaction without unconfirmed inputs is reported at height 0
+        let mut status = mempool_chain_status();
+        status.unconfirmed_inputs = HashSet::new();
+        let heights: Vec<i32> = status
+            .compute_history()
+            .into_iter()
+            .map(|(height, _)| height)
+            .collect();
+        assert_eq!(heights, vec![2, 5, 7, 0, 0, 0]);
+    }
+
+    fn miss<'a>(cache: &'a StatusCache, key: &FullHash) -> PendingStatus<'a> {
+        match cache.get(key) {
+            CachedStatus::Hit(_) => panic!("unexpected hit"),
+            CachedStatus::Miss(pending) => pending,
+        }
+    }
+
+    #[test]
+    fn test_status_cache_pending() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let cache = Arc::new(StatusCache::new(10, &metrics));
+        let key = [1; 32];
+
+        // failed and stale computations are not cached
+        drop(miss(&cache, &key));
+        let pending = miss(&cache, &key);
+        cache.invalidate(&Touched::all());
+        pending.put(Arc::new(mempool_chain_status()));
+
+        // other callers wait for the pending computation, instead of repeating it
+        let pending = miss(&cache, &key);
+        let waiter = {
+            let cache = cache.clone();
+            thread::spawn(move || match cache.get(&key) {
+                CachedStatus::Hit(status) => status.hash(),
+                CachedStatus::Miss(_) => panic!("unexpected miss"),
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        pending.put(Arc::new(mempool_chain_status()));
+        assert_eq!(waiter.join().unwrap(), mempool_chain_status().hash());
+    }
+}
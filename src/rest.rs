@@ -0,0 +1,328 @@
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize::serialize;
+use bitcoin::util::hash::Sha256dHash;
+use error_chain::ChainedError;
+use hex;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tiny_http;
+
+use query::Query;
+use util::{full_hash, spawn_thread, FullHash, HeaderEntry};
+
+use errors::*;
+
+const HTTP_THREADS: usize = 4;
+const CHAIN_TXS_PER_PAGE: usize = 25;
+const MAX_MEMPOOL_TXS: usize = 50;
+
+struct HttpError(u16, String); // (status code, message)
+
+type HttpResult<T> = ::std::result::Result<T, HttpError>;
+
+impl From<Error> for HttpError {
+    fn from(e: Error) -> HttpError {
+        if let ErrorKind::LimitExceeded(..) = *e.kind() {
+            debug!("REST request rejected: {}", e);
+            return HttpError(413, e.to_string()); // e.g. history exceeding --max-history-size
+        }
+        warn!("REST request failed: {}", e.display_chain());
+        HttpError(500, e.to_string())
+    }
+}
+
+fn not_found(what: &str) -> HttpError {
+    HttpError(404, format!("{} not found", what))
+}
+
+enum Body {
+    Json(Value),
+    Text(String),
+}
+
+fn parse_hash(hex: &str, name: &str) -> HttpResult<Sha256dHash> {
+    Sha256dHash::from_hex(hex).map_err(|_| HttpError(400, format!("invalid {}: {}", name, hex)))
+}
+
+// Script hashes are not reversed (unlike the Electrum protocol's).
+fn parse_script_hash(hash_hex: &str) -> HttpResult<FullHash> {
+    match hex::decode(hash_hex) {
+        Ok(ref hash) if hash.len() == 32 => Ok(full_hash(hash)),
+        _ => Err(HttpError(400, format!("invalid script hash: {}", hash_hex))),
+    }
+}
+
+fn status_json(header: Option<&HeaderEntry>) -> Value {
+    match header {
+        Some(entry) => json!({
+            "confirmed": true,
+            "block_height": entry.height(),
+            "block_hash": entry.hash().be_hex_string(),
+            "block_time": entry.header().time,
+        }),
+        None => json!({ "confirmed": false }),
+    }
+}
+
+fn tx_json(txn: &Transaction, header: Option<&HeaderEntry>) -> Value {
+    let vin: Vec<Value> = txn
+        .input
+        .iter()
+        .map(|input| {
+            json!({
+                "txid": input.prev_hash.be_hex_string(),
+                "vout": input.prev_index,
+                "scriptsig": hex::encode(&input.script_sig[..]),
+                "witness": input.witness.iter().map(hex::encode).collect::<Vec<String>>(),
+                "sequence": input.sequence,
+                "is_coinbase": txn.is_coin_base(),
+            })
+        })
+        .collect();
+    let vout: Vec<Value> = txn
+        .output
+        .iter()
+        .map(|output| {
+            json!({
+                "scriptpubkey": hex::encode(&output.script_pubkey[..]),
+                "value": output.value,
+            })
+        })
+        .collect();
+    json!({
+        "txid": txn.txid().be_hex_string(),
+        "version": txn.version,
+        "locktime": txn.lock_time,
+        "size": serialize(txn).unwrap().len(),
+        "weight": txn.get_weight(),
+        "vin": vin,
+        "vout": vout,
+        "status": status_json(header),
+    })
+}
+
+fn load_tx(query: &Query, txid: &Sha256dHash) -> HttpResult<(Transaction, Option<HeaderEntry>)> {
+    query
+        .get_transaction_with_header(txid)?
+        .ok_or_else(|| not_found("transaction"))
+}
+
+fn txs_json(query: &Query, txids: &[Sha256dHash]) -> HttpResult<Value> {
+    let mut txs = vec![];
+    for txid in txids {
+        let (txn, header) = load_tx(query, txid)?;
+        txs.push(tx_json(&txn, header.as_ref()));
+    }
+    Ok(json!(txs))
+}
+
+// Returns mempool transactions (newest first), and confirmed transactions (newest first).
+// The status is cached, so following pages don't recompute it.
+fn script_hash_history(
+    query: &Query,
+    script_hash: &FullHash,
+) -> HttpResult<(Vec<Sha256dHash>, Vec<Sha256dHash>)> {
+    let status = query.status(&script_hash[..])?;
+    let (mempool, confirmed): (Vec<_>, Vec<_>) = status
+        .history()
+        .iter()
+        .cloned()
+        .rev()
+        .partition(|&(height, _)| height <= 0);
+    Ok((
+        mempool.into_iter().map(|(_, txid)| txid).collect(),
+        confirmed.into_iter().map(|(_, txid)| txid).collect(),
+    ))
+}
+
+// Returns a page of confirmed transactions, following `last_seen` (if specified).
+fn chain_page<'a>(
+    txids: &'a [Sha256dHash],
+    last_seen: Option<&Sha256dHash>,
+) -> HttpResult<&'a [Sha256dHash]> {
+    let start = match last_seen {
+        Some(last_seen) => {
+            txids
+                .iter()
+                .position(|txid| txid == last_seen)
+                .ok_or_else(|| not_found("last seen transaction"))?
+                + 1
+        }
+        None => 0,
+    };
+    let end = (start + CHAIN_TXS_PER_PAGE).min(txids.len());
+    Ok(&txids[start..end])
+}
+
+fn handle_script_hash(query: &Query, hash: &str, path: &[&str]) -> HttpResult<Body> {
+    let script_hash = parse_script_hash(hash)?;
+    match path {
+        ["txs"] => {
+            let (mempool, confirmed) = script_hash_history(query, &script_hash)?;
+            let mut txids: Vec<Sha256dHash> = mempool.into_iter().take(MAX_MEMPOOL_TXS).collect();
+            txids.extend(chain_page(&confirmed, None)?);
+            Ok(Body::Json(txs_json(query, &txids)?))
+        }
+        ["txs", "mempool"] => {
+            let (mempool, _) = script_hash_history(query, &script_hash)?;
+            let txids: Vec<Sha256dHash> = mempool.into_iter().take(MAX_MEMPOOL_TXS).collect();
+            Ok(Body::Json(txs_json(query, &txids)?))
+        }
+        ["txs", "chain"] => {
+            let (_, confirmed) = script_hash_history(query, &script_hash)?;
+            Ok(Body::Json(txs_json(query, chain_page(&confirmed, None)?)?))
+        }
+        ["txs", "chain", last_seen] => {
+            let last_seen = parse_hash(last_seen, "txid")?;
+            let (_, confirmed) = script_hash_history(query, &script_hash)?;
+            let page = chain_page(&confirmed, Some(&last_seen))?;
+            Ok(Body::Json(txs_json(query, page)?))
+        }
+        ["utxo"] => {
+            let status = query.status(&script_hash[..])?;
+            let mut utxos = vec![];
+            for out in status.unspent() {
+                let header = if out.height > 0 {
+                    query.get_headers(&[out.height as usize]).pop()
+                } else {
+                    None
+                };
+                utxos.push(json!({
+                    "txid": out.txn_id.be_hex_string(),
+                    "vout": out.output_index,
+                    "value": out.value,
+                    "status": status_json(header.as_ref()),
+                }));
+            }
+            Ok(Body::Json(json!(utxos)))
+        }
+        _ => Err(not_found("endpoint")),
+    }
+}
+
+fn handle_tx(query: &Query, txid: &str, path: &[&str]) -> HttpResult<Body> {
+    let txid = parse_hash(txid, "txid")?;
+    let (txn, header) = load_tx(query, &txid)?;
+    match path {
+        [] => Ok(Body::Json(tx_json(&txn, header.as_ref()))),
+        ["hex"] => Ok(Body::Text(hex::encode(serialize(&txn).unwrap()))),
+        ["status"] => Ok(Body::Json(status_json(header.as_ref()))),
+        ["merkle-proof"] => {
+            let header = header.ok_or_else(|| not_found("confirmed transaction"))?;
+            let (merkle, pos) = query.get_merkle_proof(&txid, header.height())?;
+            let merkle: Vec<String> = merkle.into_iter().map(|h| h.be_hex_string()).collect();
+            Ok(Body::Json(json!({
+                "block_height": header.height(),
+                "merkle": merkle,
+                "pos": pos,
+            })))
+        }
+        _ => Err(not_found("endpoint")),
+    }
+}
+
+fn handle_block(query: &Query, hash: &str, path: &[&str]) -> HttpResult<Body> {
+    let blockhash = parse_hash(hash, "block hash")?;
+    let entry = query
+        .get_header_by_hash(&blockhash)
+        .ok_or_else(|| not_found("block"))?;
+    match path {
+        [] => {
+            let header = entry.header();
+            let tx_count = query.get_block_txids(&blockhash)?.len();
+            Ok(Body::Json(json!({
+                "id": blockhash.be_hex_string(),
+                "height": entry.height(),
+                "version": header.version,
+                "timestamp": header.time,
+                "tx_count": tx_count,
+                "merkle_root": header.merkle_root.be_hex_string(),
+                "previousblockhash": header.prev_blockhash.be_hex_string(),
+                "bits": header.bits,
+                "nonce": header.nonce,
+            })))
+        }
+        ["txids"] => {
+            let txids: Vec<String> = query
+                .get_block_txids(&blockhash)?
+                .into_iter()
+                .map(|txid| txid.be_hex_string())
+                .collect();
+            Ok(Body::Json(json!(txids)))
+        }
+        _ => Err(not_found("endpoint")),
+    }
+}
+
+fn handle_fee_estimates(query: &Query) -> Body {
+    let mut estimates = json!({});
+    for blocks in (1..26).chain(vec![144, 504, 1008]) {
+        let fee_rate = query.estimate_fee(blocks) * 1e5; // [sat/vB] = 10^5 [BTC/kB]
+        estimates[blocks.to_string()] = json!(fee_rate);
+    }
+    Body::Json(estimates)
+}
+
+fn handle_path(query: &Query, path: &[&str]) -> HttpResult<Body> {
+    if path.len() >= 2 {
+        let (name, id, rest) = (path[0], path[1], &path[2..]);
+        match name {
+            "tx" => return handle_tx(query, id, rest),
+            "scripthash" => return handle_script_hash(query, id, rest),
+            "block" => return handle_block(query, id, rest),
+            _ => (),
+        }
+    }
+    match path {
+        ["blocks", "tip", "height"] => {
+            Ok(Body::Text(query.get_best_header()?.height().to_string()))
+        }
+        ["blocks", "tip", "hash"] => {
+            Ok(Body::Text(query.get_best_header()?.hash().be_hex_string()))
+        }
+        ["fee-estimates"] => Ok(handle_fee_estimates(query)),
+        _ => Err(not_found("endpoint")),
+    }
+}
+
+fn handle_request(query: &Query, request: tiny_http::Request) {
+    let result = if *request.method() != tiny_http::Method::Get {
+        Err(HttpError(405, "only GET requests are supported".to_owned()))
+    } else {
+        let path = request.url().split('?').next().unwrap().to_owned();
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        handle_path(query, &parts)
+    };
+    let (status, content_type, body) = match result {
+        Ok(Body::Json(value)) => (200, "application/json", value.to_string()),
+        Ok(Body::Text(text)) => (200, "text/plain", text),
+        Err(HttpError(status, msg)) => (status, "text/plain", msg),
+    };
+    let header =
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+    let response = tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        warn!("failed to send REST response: {}", e);
+    }
+}
+
+/// Serves an Esplora-compatible HTTP REST API.
+pub fn start(addr: SocketAddr, query: Arc<Query>) {
+    let server = Arc::new(
+        tiny_http::Server::http(addr).expect(&format!("failed to start REST server at {}", addr)),
+    );
+    info!("REST server running on {}", addr);
+    for _ in 0..HTTP_THREADS {
+        let server = server.clone();
+        let query = query.clone();
+        spawn_thread("rest", move || loop {
+            match server.recv() {
+                Ok(request) => handle_request(&query, request),
+                Err(e) => error!("http error: {}", e),
+            }
+        });
+    }
+}
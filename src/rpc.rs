@@ -4,36 +4,173 @@ use bitcoin::util::address::Address;
 use bitcoin::util::hash::Sha256dHash;
 use error_chain::ChainedError;
 use hex;
-use serde_json::{from_str, Number, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use serde_json::{from_str, Value};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
 use std::str::FromStr;
-use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+use std::sync::mpsc::{channel, Receiver, Sender, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use index::compute_script_hash;
-use metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use query::{Query, Status};
-use util::{spawn_thread, Channel, HeaderEntry, SyncChannel};
+use tls::{TlsAcceptor, TlsStream};
+use util::{full_hash, spawn_thread, Channel, FullHash, HeaderEntry, SyncChannel};
+use ws;
 
 use errors::*;
 
+const SERVER_VERSION: &str = "RustElectrum 0.1.0";
+const BATCH_THREADS: usize = 4; // shared by all connections
+const RPC_THREADS: usize = 8;
+const MAX_PENDING_MESSAGES: usize = 10; // stop reading from a peer with too many pending requests
+const MAX_OUTGOING_SIZE: usize = 1 << 20; // stop handling requests of a peer not reading replies
+const MAX_HEADERS: usize = 2016; // returned by a single `blockchain.block.headers` request
+const RATE_LIMIT_BURST: f64 = 10.0; // seconds' worth of requests that may be sent at once
+
+// JSON-RPC 2.0 error codes (see https://www.jsonrpc.org/specification#error_object)
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const DAEMON_ERROR: i64 = 2; // bitcoind's own error code is returned as "data"
+const LIMIT_EXCEEDED: i64 = -101; // excessive resource usage
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProtocolVersion {
+    major: usize,
+    minor: usize,
+}
+
+const PROTOCOL_VERSION_1_2: ProtocolVersion = ProtocolVersion { major: 1, minor: 2 };
+const PROTOCOL_VERSION_1_3: ProtocolVersion = ProtocolVersion { major: 1, minor: 3 };
+const PROTOCOL_VERSION_1_4: ProtocolVersion = ProtocolVersion { major: 1, minor: 4 };
+
+const PROTOCOL_VERSION_MIN: ProtocolVersion = PROTOCOL_VERSION_1_2;
+const PROTOCOL_VERSION_MAX: ProtocolVersion = PROTOCOL_VERSION_1_4;
+
+impl FromStr for ProtocolVersion {
+    type Err = Error;
+
+    // Parses "major.minor", ignoring any trailing components (e.g. "1.4.2").
+    fn from_str(s: &str) -> Result<ProtocolVersion> {
+        let mut parts = s.split('.').map(|part| part.parse::<usize>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => Ok(ProtocolVersion { major, minor }),
+            _ => bail!(ErrorKind::InvalidParams(format!(
+                "invalid protocol version {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+fn protocol_version_from_value(val: &Value) -> Result<ProtocolVersion> {
+    val.as_str()
+        .chain_err(|| ErrorKind::InvalidParams(format!("non-string protocol version {}", val)))?
+        .parse()
+}
+
+// Client protocol version is either a single version, or a [min, max] range.
+fn protocol_range_from_value(val: Option<&Value>) -> Result<(ProtocolVersion, ProtocolVersion)> {
+    match val {
+        None => Ok((PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MIN)),
+        Some(&Value::Array(ref range)) if range.len() == 2 => Ok((
+            protocol_version_from_value(&range[0])?,
+            protocol_version_from_value(&range[1])?,
+        )),
+        Some(val) => {
+            let version = protocol_version_from_value(val)?;
+            Ok((version, version))
+        }
+    }
+}
+
+fn is_method_available(method: &str, version: ProtocolVersion) -> bool {
+    match method {
+        "blockchain.address.subscribe"
+        | "blockchain.address.get_balance"
+        | "blockchain.address.listunspent"
+        | "blockchain.address.unsubscribe" => version < PROTOCOL_VERSION_1_3,
+        "blockchain.block.get_header" => version < PROTOCOL_VERSION_1_4,
+        "blockchain.block.header" => version >= PROTOCOL_VERSION_1_3,
+        "blockchain.transaction.id_from_pos" | "blockchain.scripthash.unsubscribe" => {
+            version >= PROTOCOL_VERSION_1_4
+        }
+        _ => true,
+    }
+}
+
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
-fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
-    let script_hash = val.chain_err(|| "missing hash")?;
-    let script_hash = script_hash.as_str().chain_err(|| "non-string hash")?;
-    let script_hash = Sha256dHash::from_hex(script_hash).chain_err(|| "non-hex hash")?;
-    Ok(script_hash)
+fn hash_from_value(val: Option<&Value>, name: &str) -> Result<Sha256dHash> {
+    let hash = val.chain_err(|| ErrorKind::InvalidParams(format!("missing {}", name)))?;
+    let hash = hash
+        .as_str()
+        .chain_err(|| ErrorKind::InvalidParams(format!("non-string {}", name)))?;
+    let hash = Sha256dHash::from_hex(hash)
+        .chain_err(|| ErrorKind::InvalidParams(format!("non-hex {}", name)))?;
+    Ok(hash)
 }
 
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
-    let val = val.chain_err(|| format!("missing {}", name))?;
-    let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
+    let val = val.chain_err(|| ErrorKind::InvalidParams(format!("missing {}", name)))?;
+    let val = val
+        .as_u64()
+        .chain_err(|| ErrorKind::InvalidParams(format!("non-integer {}", name)))?;
     Ok(val as usize)
 }
 
+fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<bool> {
+    match val {
+        Some(val) => Ok(val
+            .as_bool()
+            .chain_err(|| ErrorKind::InvalidParams(format!("non-bool {}", name)))?),
+        None => Ok(default),
+    }
+}
+
+fn usize_from_value_or(val: Option<&Value>, name: &str, default: usize) -> Result<usize> {
+    if val.is_none() {
+        return Ok(default);
+    }
+    usize_from_value(val, name)
+}
+
+// Returns the script hash whose status is required for handling the request (if any).
+fn request_script_hash(method: &str, params: &[Value]) -> Option<FullHash> {
+    match method {
+        "blockchain.scripthash.subscribe"
+        | "blockchain.scripthash.get_balance"
+        | "blockchain.scripthash.get_history"
+        | "blockchain.scripthash.get_mempool"
+        | "blockchain.scripthash.listunspent" => hash_from_value(params.get(0), "script_hash")
+            .ok()
+            .map(|script_hash| full_hash(&script_hash[..])),
+        "blockchain.address.subscribe"
+        | "blockchain.address.get_balance"
+        | "blockchain.address.listunspent" => address_from_value(params.get(0))
+            .ok()
+            .map(|addr| compute_script_hash(&addr.script_pubkey().into_vec())),
+        _ => None,
+    }
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status
@@ -53,10 +190,33 @@ fn unspent_from_status(status: &Status) -> Value {
 
 fn address_from_value(val: Option<&Value>) -> Result<Address> {
     let addr = val
-        .chain_err(|| "no address")?
+        .chain_err(|| ErrorKind::InvalidParams("missing address".to_owned()))?
         .as_str()
-        .chain_err(|| "non-string address")?;
-    Address::from_str(addr).chain_err(|| format!("invalid address {}", addr))
+        .chain_err(|| ErrorKind::InvalidParams("non-string address".to_owned()))?;
+    Address::from_str(addr)
+        .chain_err(|| ErrorKind::InvalidParams(format!("invalid address {}", addr)))
+}
+
+fn error_object(id: &Value, code: i64, message: &str, data: Option<Value>) -> Value {
+    let mut error = json!({"code": code, "message": message});
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+fn error_reply(id: &Value, e: &Error) -> Value {
+    let message = e.to_string();
+    match *e.kind() {
+        ErrorKind::InvalidRequest(_) => error_object(id, INVALID_REQUEST, &message, None),
+        ErrorKind::MethodNotFound(_) => error_object(id, METHOD_NOT_FOUND, &message, None),
+        ErrorKind::InvalidParams(_) => error_object(id, INVALID_PARAMS, &message, None),
+        ErrorKind::Daemon(code, ref msg) => {
+            error_object(id, DAEMON_ERROR, msg, Some(json!({ "code": code })))
+        }
+        ErrorKind::LimitExceeded(..) => error_object(id, LIMIT_EXCEEDED, &message, None),
+        _ => error_object(id, INTERNAL_ERROR, &message, None),
+    }
 }
 
 fn jsonify_header(entry: &HeaderEntry) -> Value {
@@ -72,44 +232,285 @@ fn jsonify_header(entry: &HeaderEntry) -> Value {
     })
 }
 
+/// Electrum RPC transport, served by a listening port.
+#[derive(Clone)]
+pub enum Transport {
+    Tcp,
+    Tls(Arc<TlsAcceptor>),
+    WebSocket,
+}
+
+impl Transport {
+    fn name(&self) -> &'static str {
+        match *self {
+            Transport::Tcp => "TCP",
+            Transport::Tls(_) => "SSL",
+            Transport::WebSocket => "WebSocket",
+        }
+    }
+
+    fn accept(&self, stream: TcpStream) -> Result<Stream> {
+        Ok(match *self {
+            Transport::Tls(ref tls) => Stream::Tls(tls.accept(stream)?),
+            _ => Stream::Tcp(stream),
+        })
+    }
+
+    fn framing(&self) -> Framing {
+        match *self {
+            Transport::WebSocket => Framing::Handshake,
+            _ => Framing::Lines,
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+// Delimits the messages sent over a connection.
+enum Framing {
+    Lines,                  // newline-delimited JSON
+    Handshake,              // WebSocket opening handshake (not received yet)
+    WebSocket(ws::Decoder), // WebSocket text frames
+}
+
+/// Per-client resource limits (0 = unlimited, except for the request size).
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_connections_per_ip: usize,
+    pub max_subscriptions: usize, // per connection
+    pub max_request_size: usize,  // in bytes
+    pub max_request_rate: usize,  // per connection (in requests per second)
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9
+}
+
+// Token bucket, allowing short bursts of requests (as long as their average rate is limited).
+struct RateLimiter {
+    rate: f64,   // requests per second (0 = unlimited)
+    tokens: f64, // number of requests allowed now
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: usize) -> RateLimiter {
+        let rate = rate as f64;
+        RateLimiter {
+            rate,
+            tokens: rate * RATE_LIMIT_BURST,
+            updated: Instant::now(),
+        }
+    }
+
+    fn acquire(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = duration_secs(now.duration_since(self.updated));
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate * RATE_LIMIT_BURST);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
 struct Connection {
     query: Arc<Query>,
+    protocol_version: ProtocolVersion,
+    version_negotiated: bool, // by `server.version` (which may be sent only once)
     last_header_entry: Option<HeaderEntry>,
+    raw_headers: bool, // send headers as hex (instead of JSON objects)
     status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    prefetched: HashMap<FullHash, Arc<Status>>, // ScriptHash -> Status (for batched requests)
+    pending_update: bool, // update subscriptions after handling the current request
+    max_subscriptions: usize,
+    rate_limiter: RateLimiter,
     addr: SocketAddr,
-    chan: SyncChannel<Message>,
     stats: Arc<Stats>,
+    status_jobs: SyncSender<StatusJob>, // for prefetching batched requests' statuses
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
+        limits: &Limits,
         addr: SocketAddr,
         stats: Arc<Stats>,
+        status_jobs: SyncSender<StatusJob>,
     ) -> Connection {
         Connection {
             query,
+            protocol_version: PROTOCOL_VERSION_MIN,
+            version_negotiated: false,
             last_header_entry: None, // disable header subscription for now
+            raw_headers: false,
             status_hashes: HashMap::new(),
-            stream,
+            prefetched: HashMap::new(),
+            pending_update: false,
+            max_subscriptions: limits.max_subscriptions,
+            rate_limiter: RateLimiter::new(limits.max_request_rate),
             addr,
-            chan: SyncChannel::new(10),
             stats,
+            status_jobs,
+        }
+    }
+
+    fn check_subscription(&self, script_hash: &Sha256dHash) -> Result<()> {
+        if self.max_subscriptions > 0
+            && self.status_hashes.len() >= self.max_subscriptions
+            && !self.status_hashes.contains_key(script_hash)
+        {
+            bail!(ErrorKind::LimitExceeded(
+                "subscriptions",
+                format!(
+                    "too many subscriptions (limit is {})",
+                    self.max_subscriptions
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    fn header_value(&self, entry: &HeaderEntry) -> Value {
+        if self.raw_headers {
+            let hex_header = hex::encode(serialize(entry.header()).unwrap());
+            json!({"hex": hex_header, "height": entry.height()})
+        } else {
+            jsonify_header(entry)
+        }
+    }
+
+    fn status(&mut self, script_hash: &[u8]) -> Result<Arc<Status>> {
+        match self.prefetched.remove(&full_hash(script_hash)) {
+            Some(status) => Ok(status),
+            None => self.query.status(script_hash),
+        }
+    }
+
+    // Computes the statuses required by a batch concurrently (its requests are handled in order),
+    // skipping the requests that are going to be rejected by the rate or subscription limits.
+    fn prefetch_statuses(&mut self, cmds: &[Value], admitted: &[bool]) {
+        let mut new_subscriptions = HashSet::new();
+        let mut script_hashes = vec![];
+        for (cmd, _) in cmds.iter().zip(admitted).filter(|&(_, admitted)| *admitted) {
+            let (method, params) = match (cmd.get("method"), cmd.get("params")) {
+                (Some(&Value::String(ref method)), Some(&Value::Array(ref params))) => {
+                    (method, params)
+                }
+                _ => continue,
+            };
+            let script_hash = match request_script_hash(method, params) {
+                Some(script_hash) => script_hash,
+                None => continue,
+            };
+            if method.ends_with(".subscribe") {
+                let key: Sha256dHash = deserialize(&script_hash).unwrap();
+                if !self.status_hashes.contains_key(&key) {
+                    new_subscriptions.insert(script_hash);
+                    if self.max_subscriptions > 0
+                        && self.status_hashes.len() + new_subscriptions.len()
+                            > self.max_subscriptions
+                    {
+                        continue;
+                    }
+                }
+            }
+            script_hashes.push(script_hash);
+        }
+        script_hashes.sort_unstable();
+        script_hashes.dedup();
+        let (reply, statuses) = channel();
+        for script_hash in script_hashes {
+            let job = StatusJob {
+                script_hash,
+                reply: reply.clone(),
+            };
+            self.status_jobs
+                .send(job)
+                .expect("batch workers have stopped");
+        }
+        drop(reply); // so the statuses' iteration stops after all the jobs are done
+        for (script_hash, status) in statuses.iter() {
+            // failures are reported when the request itself is handled
+            if let Some(status) = status {
+                self.prefetched.insert(script_hash, status);
+            }
         }
     }
 
-    fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
+    fn blockchain_headers_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        // "raw" argument is deprecated since 1.3 (and removed in 1.4)
+        // (raw headers are always replied to clients not negotiating a version, as before)
+        let always_raw = !self.version_negotiated || self.protocol_version >= PROTOCOL_VERSION_1_4;
+        let default = self.protocol_version >= PROTOCOL_VERSION_1_3;
+        self.raw_headers = always_raw
+            || params
+                .get(0)
+                .and_then(|raw| raw.as_bool())
+                .unwrap_or(default);
         let entry = self.query.get_best_header()?;
-        let hex_header = hex::encode(serialize(entry.header()).unwrap());
-        let result = json!({"hex": hex_header, "height": entry.height()});
+        let result = self.header_value(&entry);
         self.last_header_entry = Some(entry);
         Ok(result)
     }
 
-    fn server_version(&self) -> Result<Value> {
-        Ok(json!(["RustElectrum 0.1.0", "1.2"]))
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        if self.version_negotiated {
+            bail!(ErrorKind::InvalidRequest(format!(
+                "protocol version {} was already negotiated",
+                self.protocol_version
+            )));
+        }
+        let client_name = params.get(0).and_then(|name| name.as_str()).unwrap_or("");
+        let (client_min, client_max) = protocol_range_from_value(params.get(1))?;
+        let min = cmp::max(client_min, PROTOCOL_VERSION_MIN);
+        let max = cmp::min(client_max, PROTOCOL_VERSION_MAX);
+        if min > max {
+            bail!(ErrorKind::InvalidParams(format!(
+                "unsupported protocol version range [{}, {}] (server supports [{}, {}])",
+                client_min, client_max, PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX
+            )));
+        }
+        self.protocol_version = max;
+        self.version_negotiated = true;
+        debug!(
+            "[{}] client {:?} negotiated protocol version {}",
+            self.addr, client_name, max
+        );
+        Ok(json!([SERVER_VERSION, max.to_string()]))
     }
 
     fn server_banner(&self) -> Result<Value> {
@@ -128,9 +529,46 @@ impl Connection {
         Ok(json!(self.query.get_fee_histogram()))
     }
 
+    fn header_proof(&self, height: usize, cp_height: usize) -> Result<(Value, Value)> {
+        let (branch, root) = self.query.get_header_merkle_proof(height, cp_height)?;
+        let branch: Vec<String> = branch
+            .into_iter()
+            .map(|hash| hash.be_hex_string())
+            .collect();
+        Ok((json!(branch), json!(root.be_hex_string())))
+    }
+
+    fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.get(0), "height")?;
+        let cp_height = if self.protocol_version >= PROTOCOL_VERSION_1_4 {
+            usize_from_value_or(params.get(1), "cp_height", 0)?
+        } else {
+            0
+        };
+        let mut entries = self.query.get_headers(&[height]);
+        let entry = entries
+            .pop()
+            .chain_err(|| format!("missing header #{}", height))?;
+        let header_hex = hex::encode(serialize(entry.header()).unwrap());
+        if cp_height == 0 {
+            return Ok(json!(header_hex));
+        }
+        let (branch, root) = self.header_proof(height, cp_height)?;
+        Ok(json!({
+            "header": header_hex,
+            "root": root,
+            "branch": branch,
+        }))
+    }
+
     fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
         let start_height = usize_from_value(params.get(0), "start_height")?;
-        let count = usize_from_value(params.get(1), "count")?;
+        let count = cmp::min(usize_from_value(params.get(1), "count")?, MAX_HEADERS);
+        let cp_height = if self.protocol_version >= PROTOCOL_VERSION_1_4 {
+            usize_from_value_or(params.get(2), "cp_height", 0)?
+        } else {
+            0
+        };
         let heights: Vec<usize> = (start_height..(start_height + count)).collect();
         let headers: Vec<String> = self
             .query
@@ -138,15 +576,22 @@ impl Connection {
             .into_iter()
             .map(|entry| hex::encode(&serialize(entry.header()).unwrap()))
             .collect();
-        Ok(json!({
+        let mut result = json!({
             "count": headers.len(),
             "hex": headers.join(""),
-            "max": 2016,
-        }))
+            "max": MAX_HEADERS,
+        });
+        if cp_height > 0 && !headers.is_empty() {
+            let last_height = start_height + headers.len() - 1;
+            let (branch, root) = self.header_proof(last_height, cp_height)?;
+            result["root"] = root;
+            result["branch"] = branch;
+        }
+        Ok(result)
     }
 
     fn blockchain_block_get_header(&self, params: &[Value]) -> Result<Value> {
-        let height = usize_from_value(params.get(0), "missing height")?;
+        let height = usize_from_value(params.get(0), "height")?;
         let mut entries = self.query.get_headers(&[height]);
         let entry = entries
             .pop()
@@ -166,87 +611,119 @@ impl Connection {
     }
 
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        self.check_subscription(&script_hash)?;
+        let status = self.status(&script_hash[..])?;
         let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
         self.status_hashes.insert(script_hash, result.clone());
         Ok(result)
     }
 
     fn blockchain_address_subscribe(&mut self, params: &[Value]) -> Result<Value> {
-        let addr = address_from_value(params.get(0)).chain_err(|| "bad address")?;
+        let addr = address_from_value(params.get(0))?;
         let script_hash = compute_script_hash(&addr.script_pubkey().into_vec());
-        let status = self.query.status(&script_hash[..])?;
-        let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
         let script_hash: Sha256dHash = deserialize(&script_hash).unwrap();
+        self.check_subscription(&script_hash)?;
+        let status = self.status(&script_hash[..])?;
+        let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
         self.status_hashes.insert(script_hash, result.clone());
         Ok(result)
     }
 
-    fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+    // Returns whether the script hash was subscribed (so it's no longer polled for updates).
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        Ok(json!(self.status_hashes.remove(&script_hash).is_some()))
+    }
+
+    fn blockchain_address_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let addr = address_from_value(params.get(0))?;
+        let script_hash = compute_script_hash(&addr.script_pubkey().into_vec());
+        let script_hash: Sha256dHash = deserialize(&script_hash).unwrap();
+        Ok(json!(self.status_hashes.remove(&script_hash).is_some()))
+    }
+
+    fn blockchain_scripthash_get_balance(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        let status = self.status(&script_hash[..])?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
     }
 
-    fn blockchain_address_get_balance(&self, params: &[Value]) -> Result<Value> {
-        let addr = address_from_value(params.get(0)).chain_err(|| "bad address")?;
+    fn blockchain_address_get_balance(&mut self, params: &[Value]) -> Result<Value> {
+        let addr = address_from_value(params.get(0))?;
         let script_hash = compute_script_hash(&addr.script_pubkey().into_vec());
-        let status = self.query.status(&script_hash[..])?;
+        let status = self.status(&script_hash[..])?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
     }
 
-    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+    fn blockchain_scripthash_get_history(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        let status = self.status(&script_hash[..])?;
         Ok(json!(Value::Array(
             status
                 .history()
-                .into_iter()
+                .iter()
                 .map(|item| json!({"height": item.0, "tx_hash": item.1.be_hex_string()}))
                 .collect()
         )))
     }
 
-    fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
-        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+    fn blockchain_scripthash_get_mempool(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        let status = self.status(&script_hash[..])?;
+        Ok(json!(Value::Array(
+            self.query
+                .get_mempool(&status)
+                .into_iter()
+                .map(|(height, txid, fee)| {
+                    json!({"height": height, "tx_hash": txid.be_hex_string(), "fee": fee})
+                })
+                .collect()
+        )))
+    }
+
+    fn blockchain_scripthash_listunspent(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0), "script_hash")?;
+        let status = self.status(&script_hash[..])?;
+        Ok(unspent_from_status(&status))
     }
 
-    fn blockchain_address_listunspent(&self, params: &[Value]) -> Result<Value> {
-        let addr = address_from_value(params.get(0)).chain_err(|| "bad address")?;
+    fn blockchain_address_listunspent(&mut self, params: &[Value]) -> Result<Value> {
+        let addr = address_from_value(params.get(0))?;
         let script_hash = compute_script_hash(&addr.script_pubkey().into_vec());
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+        let status = self.status(&script_hash[..])?;
+        Ok(unspent_from_status(&status))
     }
 
-    fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
-        let tx = params.get(0).chain_err(|| "missing tx")?;
-        let tx = tx.as_str().chain_err(|| "non-string tx")?;
-        let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
-        let tx: Transaction = deserialize(&tx).chain_err(|| "failed to parse tx")?;
+    fn blockchain_transaction_broadcast(&mut self, params: &[Value]) -> Result<Value> {
+        let tx = params
+            .get(0)
+            .chain_err(|| ErrorKind::InvalidParams("missing tx".to_owned()))?;
+        let tx = tx
+            .as_str()
+            .chain_err(|| ErrorKind::InvalidParams("non-string tx".to_owned()))?;
+        let tx =
+            hex::decode(&tx).chain_err(|| ErrorKind::InvalidParams("non-hex tx".to_owned()))?;
+        let tx: Transaction = deserialize(&tx)
+            .chain_err(|| ErrorKind::InvalidParams("failed to parse tx".to_owned()))?;
         let txid = self.query.broadcast(&tx)?;
         self.query.update_mempool()?;
-        if let Err(e) = self.chan.sender().try_send(Message::PeriodicUpdate) {
-            warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
-        }
+        self.pending_update = true;
         Ok(json!(txid.be_hex_string()))
     }
 
     fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
-        let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
-        let verbose = match params.get(1) {
-            Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
-            None => false,
-        };
+        let tx_hash = hash_from_value(params.get(0), "tx_hash")?;
+        let verbose = bool_from_value_or(params.get(1), "verbose", false)?;
         Ok(self.query.get_transaction(&tx_hash, verbose)?)
     }
 
     fn blockchain_transaction_get_merkle(&self, params: &[Value]) -> Result<Value> {
-        let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
+        let tx_hash = hash_from_value(params.get(0), "tx_hash")?;
         let height = usize_from_value(params.get(1), "height")?;
         let (merkle, pos) = self
             .query
@@ -262,19 +739,38 @@ impl Connection {
                 "pos": pos}))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Number) -> Result<Value> {
-        let timer = self
-            .stats
-            .latency
-            .with_label_values(&[method])
-            .start_timer();
-        let result = match method {
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
-            "server.version" => self.server_version(),
+    fn blockchain_transaction_id_from_pos(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.get(0), "height")?;
+        let tx_pos = usize_from_value(params.get(1), "tx_pos")?;
+        let want_merkle = bool_from_value_or(params.get(2), "merkle", false)?;
+        let (txid, merkle) = self.query.get_id_from_pos(height, tx_pos, want_merkle)?;
+        if !want_merkle {
+            return Ok(json!(txid.be_hex_string()));
+        }
+        let merkle: Vec<String> = merkle
+            .into_iter()
+            .map(|txid| txid.be_hex_string())
+            .collect();
+        Ok(json!({
+            "tx_hash": txid.be_hex_string(),
+            "merkle": merkle}))
+    }
+
+    fn dispatch(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        match method {
+            _ if !is_method_available(method, self.protocol_version) => {
+                bail!(ErrorKind::MethodNotFound(format!(
+                    "method {} is not available in protocol version {}",
+                    method, self.protocol_version
+                )))
+            }
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(&params),
+            "server.version" => self.server_version(&params),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "blockchain.block.header" => self.blockchain_block_header(&params),
             "blockchain.block.headers" => self.blockchain_block_headers(&params),
             "blockchain.block.get_header" => self.blockchain_block_get_header(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
@@ -282,30 +778,79 @@ impl Connection {
             "blockchain.address.subscribe" => self.blockchain_address_subscribe(&params),
             "blockchain.address.get_balance" => self.blockchain_address_get_balance(&params),
             "blockchain.address.listunspent" => self.blockchain_address_listunspent(&params),
+            "blockchain.address.unsubscribe" => self.blockchain_address_unsubscribe(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
-            &_ => bail!("unknown method {} {:?}", method, params),
+            "blockchain.transaction.id_from_pos" => {
+                self.blockchain_transaction_id_from_pos(&params)
+            }
+            &_ => bail!(ErrorKind::MethodNotFound(format!(
+                "unknown method {}",
+                method
+            ))),
+        }
+    }
+
+    fn timed_dispatch(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        let start = Instant::now();
+        let result = self.dispatch(method, params);
+        // avoid creating a new metric label for each unknown method name
+        let label = match result {
+            Err(Error(ErrorKind::MethodNotFound(_), _)) => "unknown",
+            _ => method,
         };
-        timer.observe_duration();
-        // TODO: return application errors should be sent to the client
-        Ok(match result {
+        self.stats
+            .latency
+            .with_label_values(&[label])
+            .observe(duration_secs(start.elapsed()));
+        result
+    }
+
+    // `admitted` is false if the request was rejected by the rate limiter.
+    fn handle_command(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        id: &Value,
+        admitted: bool,
+    ) -> Value {
+        let result = if admitted {
+            self.timed_dispatch(method, params)
+        } else {
+            Err(ErrorKind::LimitExceeded(
+                "request_rate",
+                format!(
+                    "too many requests (limit is {} per second)",
+                    self.rate_limiter.rate
+                ),
+            )
+            .into())
+        };
+        match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
-                warn!(
-                    "rpc #{} {} {:?} failed: {}",
-                    id,
-                    method,
-                    params,
-                    e.display_chain()
-                );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                if let ErrorKind::LimitExceeded(limit, _) = *e.kind() {
+                    self.stats.limits.with_label_values(&[limit]).inc();
+                    debug!("[{}] rpc #{} {} rejected: {}", self.addr, id, method, e);
+                } else {
+                    warn!(
+                        "rpc #{} {} {:?} failed: {}",
+                        id,
+                        method,
+                        params,
+                        e.display_chain()
+                    );
+                }
+                error_reply(id, &e)
             }
-        })
+        }
     }
 
     fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
@@ -315,20 +860,41 @@ impl Connection {
             .with_label_values(&["periodic_update"])
             .start_timer();
         let mut result = vec![];
-        if let Some(ref mut last_entry) = self.last_header_entry {
+        if let Some(last_entry) = self.last_header_entry.clone() {
             let entry = self.query.get_best_header()?;
-            if *last_entry != entry {
-                *last_entry = entry;
-                let hex_header = hex::encode(serialize(last_entry.header()).unwrap());
-                let header = json!({"hex": hex_header, "height": last_entry.height()});
+            if last_entry != entry {
+                let header = self.header_value(&entry);
+                self.last_header_entry = Some(entry);
                 result.push(json!({
                     "jsonrpc": "2.0",
                     "method": "blockchain.headers.subscribe",
                     "params": [header]}));
             }
         }
+        let mut dropped = vec![];
         for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let status = self.query.status(&script_hash[..])?;
+            let status = match self.query.status(&script_hash[..]) {
+                Ok(status) => status,
+                Err(e) => {
+                    let limit = match *e.kind() {
+                        ErrorKind::LimitExceeded(limit, _) => limit,
+                        _ => return Err(e),
+                    };
+                    // notify the client, instead of closing its connection
+                    self.stats.limits.with_label_values(&[limit]).inc();
+                    debug!(
+                        "[{}] dropped subscription to {}: {}",
+                        self.addr,
+                        script_hash.be_hex_string(),
+                        e
+                    );
+                    let data = json!({ "scripthash": script_hash.be_hex_string() });
+                    let msg = e.to_string();
+                    result.push(error_object(&Value::Null, LIMIT_EXCEEDED, &msg, Some(data)));
+                    dropped.push(*script_hash);
+                    continue;
+                }
+            };
             let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
             if new_status_hash == *status_hash {
                 continue;
@@ -339,6 +905,9 @@ impl Connection {
                 "params": [script_hash.be_hex_string(), new_status_hash]}));
             *status_hash = new_status_hash;
         }
+        for script_hash in dropped {
+            self.status_hashes.remove(&script_hash);
+        }
         timer.observe_duration();
         self.stats
             .subscriptions
@@ -346,96 +915,263 @@ impl Connection {
         Ok(result)
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
-        for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+    fn handle_value(&mut self, cmd: &Value, admitted: bool) -> Value {
+        let id = cmd.get("id").unwrap_or(&Value::Null);
+        match (cmd.get("method"), cmd.get("params")) {
+            (Some(&Value::String(ref method)), Some(&Value::Array(ref params))) => {
+                self.handle_command(method, params, id, admitted)
+            }
+            (Some(&Value::String(ref method)), None) => {
+                self.handle_command(method, &[], id, admitted)
+            }
+            _ => {
+                warn!("[{}] invalid request: {}", self.addr, cmd);
+                error_object(id, INVALID_REQUEST, "invalid request", None)
+            }
         }
-        Ok(())
     }
 
-    fn handle_replies(&mut self) -> Result<()> {
-        loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
-            trace!("RPC {:?}", msg);
-            match msg {
-                Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (cmd.get("method"), cmd.get("params"), cmd.get("id")) {
-                        (
-                            Some(&Value::String(ref method)),
-                            Some(&Value::Array(ref params)),
-                            Some(&Value::Number(ref id)),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
+    fn handle_batch(&mut self, cmds: &[Value]) -> Value {
+        if cmds.is_empty() {
+            return error_object(&Value::Null, INVALID_REQUEST, "empty batch", None);
+        }
+        // each request is charged before prefetching, so a batch can't exceed the rate limit
+        let admitted: Vec<bool> = cmds.iter().map(|_| self.rate_limiter.acquire()).collect();
+        self.prefetch_statuses(cmds, &admitted);
+        let replies: Vec<Value> = cmds
+            .iter()
+            .zip(admitted)
+            .map(|(cmd, admitted)| self.handle_value(cmd, admitted))
+            .collect();
+        self.prefetched.clear();
+        Value::Array(replies)
+    }
+
+    fn handle_message(&mut self, msg: Message) -> Result<Vec<Value>> {
+        trace!("RPC {:?}", msg);
+        let mut values = vec![];
+        if let Message::Request(line) = msg {
+            values.push(match from_str(&line) {
+                Ok(Value::Array(ref cmds)) => self.handle_batch(cmds),
+                Ok(cmd) => {
+                    let admitted = self.rate_limiter.acquire();
+                    self.handle_value(&cmd, admitted)
                 }
-                Message::PeriodicUpdate => {
-                    let values = self
-                        .update_subscriptions()
-                        .chain_err(|| "failed to update subscriptions")?;
-                    self.send_values(&values)?
+                Err(e) => {
+                    warn!("[{}] invalid JSON {:?}: {}", self.addr, line, e);
+                    error_object(&Value::Null, PARSE_ERROR, "parse error", None)
+                }
+            });
+            if !mem::replace(&mut self.pending_update, false) {
+                return Ok(values);
+            }
+        }
+        values.extend(
+            self.update_subscriptions()
+                .chain_err(|| "failed to update subscriptions")?,
+        );
+        Ok(values)
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Request(String),
+    PeriodicUpdate,
+}
+
+// A connected peer, whose socket is handled by the event loop.
+struct Peer {
+    stream: Stream,
+    addr: SocketAddr,
+    framing: Framing,
+    incoming: Vec<u8>,           // received data (not parsed yet)
+    outgoing: Vec<u8>,           // replies (not sent yet)
+    messages: VecDeque<Message>, // parsed messages (not handled yet)
+    conn: Option<Connection>,    // None while a message is being handled by a worker
+    eof: bool,                   // no more messages will be received
+    max_request_size: usize,
+}
+
+impl Peer {
+    fn new(
+        stream: Stream,
+        addr: SocketAddr,
+        framing: Framing,
+        conn: Connection,
+        max_request_size: usize,
+    ) -> Peer {
+        Peer {
+            stream,
+            addr,
+            framing,
+            incoming: vec![],
+            outgoing: vec![],
+            messages: VecDeque::new(),
+            conn: Some(conn),
+            eof: false,
+            max_request_size,
+        }
+    }
+
+    fn receive(&mut self) -> Result<()> {
+        let mut buf = [0u8; 16 * 1024];
+        while !self.eof && self.messages.len() < MAX_PENDING_MESSAGES {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.incoming.extend_from_slice(&buf[..n]);
+                    self.parse()?;
                 }
-                Message::Done => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| "failed to read a request"),
             }
         }
+        Ok(())
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn parse(&mut self) -> Result<()> {
         loop {
-            let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
-                return Ok(());
-            } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
-                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+            let msg = match self.framing {
+                Framing::Lines => {
+                    let max_size = self.max_request_size;
+                    match self
+                        .incoming
+                        .iter()
+                        .take(max_size)
+                        .position(|b| *b == b'\n')
+                    {
+                        Some(pos) => {
+                            let line: Vec<u8> = self.incoming.drain(..pos + 1).collect();
+                            if line.starts_with(&[22, 3, 1]) {
+                                // (very) naive SSL handshake detection
+                                bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+                            }
+                            let line = String::from_utf8(line).chain_err(|| "invalid UTF8")?;
+                            Message::Request(line)
+                        }
+                        None if self.incoming.len() >= max_size => bail!(ErrorKind::LimitExceeded(
+                            "request_size",
+                            format!("request is larger than {} bytes", max_size)
+                        )),
+                        None => return Ok(()),
+                    }
                 }
-                match String::from_utf8(line) {
-                    Ok(req) => tx
-                        .send(Message::Request(req))
-                        .chain_err(|| "channel closed")?,
-                    Err(err) => {
-                        let _ = tx.send(Message::Done);
-                        bail!("invalid UTF8: {}", err)
+                Framing::Handshake => match ws::accept(&self.incoming) {
+                    Ok(Some((len, response))) => {
+                        self.incoming.drain(..len);
+                        self.outgoing.extend(response);
+                        self.framing = Framing::WebSocket(ws::Decoder::new(self.max_request_size));
+                        continue;
+                    }
+                    Ok(None) => return Ok(()),
+                    Err(e) => {
+                        self.outgoing.extend_from_slice(ws::BAD_REQUEST);
+                        return Err(e);
                     }
+                },
+                Framing::WebSocket(ref mut decoder) => match decoder.decode(&mut self.incoming)? {
+                    Some(ws::Incoming::Text(text)) => Message::Request(text),
+                    Some(ws::Incoming::Ping(payload)) => {
+                        self.outgoing.extend(ws::encode_pong(&payload));
+                        continue;
+                    }
+                    Some(ws::Incoming::Close) => {
+                        self.incoming.clear();
+                        self.eof = true;
+                        return Ok(());
+                    }
+                    None => return Ok(()),
+                },
+            };
+            self.messages.push_back(msg);
+        }
+    }
+
+    fn send(&mut self, values: &[Value]) {
+        for value in values {
+            let text = value.to_string();
+            match self.framing {
+                Framing::WebSocket(_) => self.outgoing.extend(ws::encode_text(&text)),
+                _ => {
+                    self.outgoing.extend(text.as_bytes());
+                    self.outgoing.push(b'\n');
                 }
             }
         }
     }
 
-    pub fn run(mut self) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
-        let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
-            error!(
-                "[{}] connection handling failed: {}",
-                self.addr,
-                e.display_chain().to_string()
-            );
+    fn flush(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => bail!("failed to write: connection closed"),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| "failed to write"),
+            }
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self) {
+        let pending = self.messages.iter().any(|msg| match *msg {
+            Message::PeriodicUpdate => true,
+            _ => false,
+        });
+        if !pending {
+            self.messages.push_back(Message::PeriodicUpdate);
+        }
+    }
+
+    // Sends the next message to a worker (unless the previous one is still being handled).
+    fn dispatch(&mut self, token: Token, jobs: &Sender<Job>) -> bool {
+        if self.conn.is_none() || self.outgoing.len() >= MAX_OUTGOING_SIZE {
+            return false;
         }
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
-        if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
+        let msg = match self.messages.pop_front() {
+            Some(msg) => msg,
+            None => return false,
+        };
+        let conn = self.conn.take().unwrap();
+        jobs.send(Job { token, conn, msg })
+            .expect("RPC workers have stopped");
+        true
+    }
+
+    fn process(&mut self, token: Token, jobs: &Sender<Job>) -> Result<()> {
+        loop {
+            self.receive()?;
+            self.flush()?;
+            if !self.dispatch(token, jobs) {
+                return Ok(());
+            }
         }
     }
+
+    fn is_done(&self) -> bool {
+        self.eof && self.conn.is_some() && self.messages.is_empty() && self.outgoing.is_empty()
+    }
 }
 
-#[derive(Debug)]
-pub enum Message {
-    Request(String),
-    PeriodicUpdate,
-    Done,
+struct Job {
+    token: Token,
+    conn: Connection,
+    msg: Message,
+}
+
+struct StatusJob {
+    script_hash: FullHash,
+    reply: Sender<(FullHash, Option<Arc<Status>>)>,
+}
+
+struct Completion {
+    token: Token,
+    conn: Connection,
+    result: Result<Vec<Value>>,
 }
 
 pub enum Notification {
@@ -443,55 +1179,269 @@ pub enum Notification {
     Exit,
 }
 
+const WAKER: Token = Token(0); // listeners use the following tokens
+
+// Handles all connections' sockets using non-blocking I/O, while messages are handled by workers.
+struct Server {
+    poll: Poll,
+    waker: (Registration, SetReadiness), // signals worker completions and notifications
+    listeners: Vec<(TcpListener, Transport)>,
+    peers: HashMap<Token, Peer>,
+    next_token: usize,
+    connections: HashMap<IpAddr, usize>, // number of peers connected from each IP
+    limits: Limits,
+    jobs: Sender<Job>,
+    status_jobs: SyncSender<StatusJob>,
+    query: Arc<Query>,
+    stats: Arc<Stats>,
+}
+
+impl Server {
+    fn accept(&mut self, index: usize) {
+        loop {
+            let (mut stream, addr) = match self.listeners[index].0.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    return;
+                }
+            };
+            let max_connections = self.limits.max_connections_per_ip;
+            let count = self.connections.get(&addr.ip()).cloned().unwrap_or(0);
+            if max_connections > 0 && count >= max_connections {
+                self.stats
+                    .limits
+                    .with_label_values(&["connections_per_ip"])
+                    .inc();
+                debug!(
+                    "[{}] rejected peer: {} connections from its IP",
+                    addr, count
+                );
+                if let Transport::Tcp = self.listeners[index].1 {
+                    let msg = format!("too many connections (limit is {} per IP)", max_connections);
+                    let reply = error_object(&Value::Null, LIMIT_EXCEEDED, &msg, None);
+                    let _ = stream.write_all((reply.to_string() + "\n").as_bytes());
+                }
+                continue;
+            }
+            info!("[{}] connected peer", addr);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let interest = Ready::readable() | Ready::writable();
+            if let Err(e) = self
+                .poll
+                .register(&stream, token, interest, PollOpt::edge())
+            {
+                warn!("[{}] failed to register: {}", addr, e);
+                continue;
+            }
+            let transport = &self.listeners[index].1;
+            let stream = match transport.accept(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("[{}] {}", addr, e.display_chain());
+                    continue;
+                }
+            };
+            let conn = Connection::new(
+                self.query.clone(),
+                &self.limits,
+                addr,
+                self.stats.clone(),
+                self.status_jobs.clone(),
+            );
+            let max_request_size = self.limits.max_request_size;
+            let peer = Peer::new(stream, addr, transport.framing(), conn, max_request_size);
+            self.peers.insert(token, peer);
+            *self.connections.entry(addr.ip()).or_insert(0) += 1;
+        }
+    }
+
+    fn process(&mut self, token: Token) {
+        let done = match self.peers.get_mut(&token) {
+            Some(peer) => match peer.process(token, &self.jobs) {
+                Ok(()) => peer.is_done(),
+                Err(e) => {
+                    warn!("[{}] {}", peer.addr, e.display_chain());
+                    if let ErrorKind::LimitExceeded(limit, _) = *e.kind() {
+                        self.stats.limits.with_label_values(&[limit]).inc();
+                        peer.send(&[error_reply(&Value::Null, &e)]);
+                    }
+                    let _ = peer.flush();
+                    true
+                }
+            },
+            None => false,
+        };
+        if done {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(peer) = self.peers.remove(&token) {
+            info!("[{}] disconnected peer", peer.addr);
+            let ip = peer.addr.ip();
+            let count = self.connections[&ip] - 1;
+            if count == 0 {
+                self.connections.remove(&ip);
+            } else {
+                self.connections.insert(ip, count);
+            }
+        }
+    }
+
+    fn complete(&mut self, completion: Completion) {
+        let Completion {
+            token,
+            conn,
+            result,
+        } = completion;
+        match result {
+            Ok(values) => {
+                if let Some(peer) = self.peers.get_mut(&token) {
+                    peer.send(&values);
+                    peer.conn = Some(conn);
+                }
+                self.process(token);
+            }
+            Err(e) => {
+                error!(
+                    "[{}] connection handling failed: {}",
+                    conn.addr,
+                    e.display_chain().to_string()
+                );
+                self.close(token);
+            }
+        }
+    }
+
+    fn run(&mut self, notification: &Receiver<Notification>, completions: &Receiver<Completion>) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("poll failed: {}", e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.waker.1.set_readiness(Ready::empty()).unwrap(),
+                    Token(i) if i <= self.listeners.len() => self.accept(i - 1),
+                    token => self.process(token),
+                }
+            }
+            for msg in notification.try_iter() {
+                match msg {
+                    Notification::Periodic => {
+                        let tokens: Vec<Token> = self.peers.keys().cloned().collect();
+                        for token in tokens {
+                            self.peers.get_mut(&token).unwrap().notify();
+                            self.process(token);
+                        }
+                    }
+                    Notification::Exit => return,
+                }
+            }
+            for completion in completions.try_iter() {
+                self.complete(completion);
+            }
+        }
+    }
+}
+
 pub struct RPC {
     notification: Sender<Notification>,
+    waker: SetReadiness,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
 }
 
 struct Stats {
     latency: HistogramVec,
     subscriptions: Gauge,
+    limits: CounterVec,
 }
 
 impl RPC {
-    fn start_notifier(
-        notification: Channel<Notification>,
-        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
-    ) {
-        spawn_thread("notification", move || {
-            for msg in notification.receiver().iter() {
-                let mut senders = senders.lock().unwrap();
-                match msg {
-                    Notification::Periodic => for sender in senders.split_off(0) {
-                        if let Err(TrySendError::Disconnected(_)) =
-                            sender.try_send(Message::PeriodicUpdate)
-                        {
-                            continue;
-                        }
-                        senders.push(sender);
-                    },
-                    Notification::Exit => acceptor.send(None).unwrap(),
-                }
-            }
-        });
+    fn start_workers(
+        completions: Sender<Completion>,
+        waker: SetReadiness,
+    ) -> (Sender<Job>, Vec<thread::JoinHandle<()>>) {
+        let jobs = Channel::<Job>::new();
+        let sender = jobs.sender();
+        let receiver = Arc::new(Mutex::new(jobs.into_receiver()));
+        let workers = (0..RPC_THREADS)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let completions = completions.clone();
+                let waker = waker.clone();
+                spawn_thread("rpc_worker", move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let Job {
+                        token,
+                        mut conn,
+                        msg,
+                    } = match job {
+                        Ok(job) => job,
+                        Err(_) => return, // server has stopped
+                    };
+                    // the peer is closed (by failing its message) if the handler panics
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| conn.handle_message(msg)))
+                        .unwrap_or_else(|_| bail!("RPC handler panicked"));
+                    if completions
+                        .send(Completion {
+                            token,
+                            conn,
+                            result,
+                        })
+                        .is_err()
+                    {
+                        return;
+                    }
+                    waker.set_readiness(Ready::readable()).unwrap();
+                })
+            })
+            .collect();
+        (sender, workers)
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::new();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let listener = TcpListener::bind(addr).expect(&format!("bind({}) failed", addr));
-            info!("RPC server running on {}", addr);
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
-        });
-        chan
+    // Computes the statuses of batched requests (the queue is bounded, so connections
+    // wait for the workers instead of accumulating jobs).
+    fn start_batch_workers(
+        query: Arc<Query>,
+    ) -> (SyncSender<StatusJob>, Vec<thread::JoinHandle<()>>) {
+        let jobs = SyncChannel::<StatusJob>::new(BATCH_THREADS);
+        let sender = jobs.sender();
+        let receiver = Arc::new(Mutex::new(jobs.into_receiver()));
+        let workers = (0..BATCH_THREADS)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let query = query.clone();
+                spawn_thread("batch_worker", move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let StatusJob { script_hash, reply } = match job {
+                        Ok(job) => job,
+                        Err(_) => return, // server has stopped
+                    };
+                    let status = panic::catch_unwind(AssertUnwindSafe(|| {
+                        query.status(&script_hash[..]).ok()
+                    }))
+                    .unwrap_or(None);
+                    let _ = reply.send((script_hash, status)); // the batch may have failed
+                })
+            })
+            .collect();
+        (sender, workers)
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics) -> RPC {
+    pub fn start(
+        listeners: Vec<(SocketAddr, Transport)>,
+        limits: Limits,
+        query: Arc<Query>,
+        metrics: &Metrics,
+    ) -> RPC {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -501,33 +1451,55 @@ impl RPC {
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            limits: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_limit_violations",
+                    "# of Electrum requests/connections rejected due to resource limits",
+                ),
+                &["limit"],
+            ),
         });
+        let poll = Poll::new().expect("failed to create poll");
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())
+            .expect("failed to register waker");
+        let listeners: Vec<(TcpListener, Transport)> = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, (addr, transport))| {
+                let listener = TcpListener::bind(&addr).expect(&format!("bind({}) failed", addr));
+                poll.register(&listener, Token(i + 1), Ready::readable(), PollOpt::edge())
+                    .expect("failed to register listener");
+                info!("RPC server running on {} ({})", addr, transport.name());
+                (listener, transport)
+            })
+            .collect();
         let notification = Channel::new();
         let handle = RPC {
             notification: notification.sender(),
+            waker: waker.clone(),
             server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
-                let acceptor = RPC::start_acceptor(addr);
-                RPC::start_notifier(notification, senders.clone(), acceptor.sender());
-                let mut children = vec![];
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
-                    let query = query.clone();
-                    let senders = senders.clone();
-                    let stats = stats.clone();
-                    children.push(spawn_thread("peer", move || {
-                        info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats);
-                        senders.lock().unwrap().push(conn.chan.sender());
-                        conn.run();
-                        info!("[{}] disconnected peer", addr);
-                    }));
-                }
+                let completions = Channel::new();
+                let (jobs, workers) = RPC::start_workers(completions.sender(), waker.clone());
+                let (status_jobs, batch_workers) = RPC::start_batch_workers(query.clone());
+                let mut server = Server {
+                    poll,
+                    waker: (registration, waker),
+                    next_token: listeners.len() + 1,
+                    listeners,
+                    peers: HashMap::new(),
+                    connections: HashMap::new(),
+                    limits,
+                    jobs,
+                    status_jobs,
+                    query,
+                    stats,
+                };
+                server.run(notification.receiver(), completions.receiver());
                 trace!("closing RPC connections");
-                for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
-                }
-                for child in children {
-                    let _ = child.join();
+                drop(server);
+                for worker in workers.into_iter().chain(batch_workers) {
+                    let _ = worker.join();
                 }
                 trace!("RPC connections are closed");
             })),
@@ -535,15 +1507,20 @@ impl RPC {
         handle
     }
 
+    fn send(&self, msg: Notification) {
+        self.notification.send(msg).unwrap();
+        self.waker.set_readiness(Ready::readable()).unwrap();
+    }
+
     pub fn notify(&self) {
-        self.notification.send(Notification::Periodic).unwrap();
+        self.send(Notification::Periodic);
     }
 }
 
 impl Drop for RPC {
     fn drop(&mut self) {
         trace!("stop accepting new RPCs");
-        self.notification.send(Notification::Exit).unwrap();
+        self.send(Notification::Exit);
         self.server.take().map(|t| t.join().unwrap());
         trace!("RPC server is stopped");
     }
@@ -28,6 +28,21 @@ impl Waiter {
         }
         Ok(())
     }
+    /// Returns the first event received before the timeout (if any).
+    pub fn wait_for<T>(&self, duration: Duration, events: &chan::Receiver<T>) -> Result<Option<T>> {
+        let signal = &self.signal;
+        let timeout = chan::after(duration);
+        chan_select! {
+            signal.recv() -> s => {
+                if let Some(sig) = s {
+                    bail!(ErrorKind::Interrupt(sig));
+                }
+            },
+            events.recv() -> event => return Ok(event),
+            timeout.recv() => {},
+        }
+        Ok(None)
+    }
     pub fn poll(&self) -> Result<()> {
         self.wait(Duration::from_secs(0))
     }
@@ -1,9 +1,14 @@
 use rocksdb;
 
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use migrate;
 use util::Bytes;
 
+use errors::*;
+
 #[derive(Clone)]
 pub struct Row {
     pub key: Bytes,
@@ -16,13 +21,38 @@ impl Row {
     }
 }
 
+pub type ScanIterator<'a> = Box<Iterator<Item = Row> + 'a>;
+
 pub trait ReadStore: Sync {
     fn get(&self, key: &[u8]) -> Option<Bytes>;
-    fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+
+    /// Lazily iterates over the rows whose keys start with `prefix`.
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> ScanIterator<'a>;
+
+    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        self.iter_scan(prefix).collect()
+    }
 }
 
-pub trait WriteStore: Sync {
+fn prefix_rows<'a, I>(iter: I, prefix: &[u8]) -> ScanIterator<'a>
+where
+    I: Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a,
+{
+    let prefix = prefix.to_vec();
+    Box::new(
+        iter.take_while(move |(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| Row {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }),
+    )
+}
+
+pub trait WriteStore: ReadStore {
     fn write(&self, rows: Vec<Row>);
+    fn delete(&self, keys: Vec<Bytes>);
+    /// Deletes the keys and writes the rows in a single atomic batch.
+    fn delete_and_write(&self, keys: Vec<Bytes>, rows: Vec<Row>);
     fn flush(&self);
 }
 
@@ -33,10 +63,20 @@ struct Options {
 }
 
 pub struct DBStore {
-    db: rocksdb::DB,
+    db: Arc<rocksdb::DB>,
     opts: Options,
 }
 
+/// A consistent read-only view of the DB, taken after a successful indexing.
+pub struct SnapshotStore {
+    snapshot: rocksdb::Snapshot<'static>, // must be dropped before the DB
+    _db: Arc<rocksdb::DB>,
+}
+
+// RocksDB snapshots are immutable, so they can be shared between threads.
+unsafe impl Send for SnapshotStore {}
+unsafe impl Sync for SnapshotStore {}
+
 impl DBStore {
     fn open_opts(opts: Options) -> Self {
         debug!("opening DB at {:?}", opts.path);
@@ -55,40 +95,81 @@ impl DBStore {
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(1 << 20);
         DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
+            db: Arc::new(rocksdb::DB::open(&db_opts, &opts.path).unwrap()),
             opts,
         }
     }
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path) -> Self {
-        DBStore::open_opts(Options {
+    /// Opens a new RocksDB at the specified location, checking its schema version
+    /// (see `migrate::upgrade` for upgrading older DBs).
+    pub fn open(path: &Path) -> Result<Self> {
+        let store = DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
+        });
+        migrate::check_version(store).chain_err(|| format!("failed to open DB at {:?}", path))
+    }
+
+    /// Opens an existing RocksDB without checking (or setting) its schema version,
+    /// so no rows are written to it unless requested.
+    pub fn open_unchecked(path: &Path) -> Self {
+        DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: true, // no background compactions
         })
     }
 
-    pub fn enable_compaction(self) -> Self {
+    /// Writes a single row to the WAL, so it persists even during bulk import.
+    pub fn put_sync(&self, row: Row) {
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_opt(row.key.as_slice(), row.value.as_slice(), &opts)
+            .unwrap();
+    }
+
+    fn reopen(self, opts: Options) -> Result<Self> {
+        // each snapshot holds a reference to the DB, and would outlive it
+        let snapshots = Arc::strong_count(&self.db) - 1;
+        if snapshots > 0 {
+            bail!(
+                "cannot re-open DB at {:?}: {} snapshots are in use",
+                opts.path,
+                snapshots
+            );
+        }
+        drop(self); // DB must be closed before being re-opened
+        Ok(DBStore::open_opts(opts))
+    }
+
+    pub fn enable_compaction(self) -> Result<Self> {
         let mut opts = self.opts.clone();
         if opts.bulk_import == true {
             opts.bulk_import = false;
-            drop(self); // DB must be closed before being re-opened
             info!("enabling auto-compactions");
-            DBStore::open_opts(opts)
+            self.reopen(opts)
         } else {
-            self
+            Ok(self)
         }
     }
 
-    pub fn compact(self) -> Self {
+    pub fn compact(self) -> Result<Self> {
         let opts = self.opts.clone();
-        drop(self); // DB must be closed before being re-opened
-
-        let store = DBStore::open_opts(opts);
+        let store = self.reopen(opts)?;
         info!("starting full compaction");
         store.db.compact_range(None, None); // would take a while
         info!("finished full compaction");
-        store
+        Ok(store)
+    }
+
+    pub fn snapshot(&self) -> SnapshotStore {
+        let db = self.db.clone();
+        // The snapshot borrows the DB, which is kept alive by the `Arc` stored alongside it.
+        let snapshot = unsafe {
+            mem::transmute::<rocksdb::Snapshot, rocksdb::Snapshot<'static>>(db.snapshot())
+        };
+        SnapshotStore { snapshot, _db: db }
     }
 }
 
@@ -97,28 +178,37 @@ impl ReadStore for DBStore {
         self.db.get(key).unwrap().map(|v| v.to_vec())
     }
 
-    // TODO: use generators
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
-            prefix,
-            rocksdb::Direction::Forward,
-        )) {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
-        }
-        rows
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> ScanIterator<'a> {
+        let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+        prefix_rows(self.db.iterator(mode), prefix)
+    }
+}
+
+impl ReadStore for SnapshotStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.snapshot.get(key).unwrap().map(|v| v.to_vec())
+    }
+
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> ScanIterator<'a> {
+        let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+        prefix_rows(self.snapshot.iterator(mode), prefix)
     }
 }
 
 impl WriteStore for DBStore {
     fn write(&self, rows: Vec<Row>) {
+        self.delete_and_write(vec![], rows);
+    }
+
+    fn delete(&self, keys: Vec<Bytes>) {
+        self.delete_and_write(keys, vec![]);
+    }
+
+    fn delete_and_write(&self, keys: Vec<Bytes>, rows: Vec<Row>) {
         let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete(key.as_slice()).unwrap();
+        }
         for row in rows {
             batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
         }
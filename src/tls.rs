@@ -0,0 +1,96 @@
+use mio::net::TcpStream;
+use openssl::ssl::{
+    HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod, SslStream,
+};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use errors::*;
+
+pub struct TlsAcceptor {
+    acceptor: SslAcceptor,
+}
+
+impl TlsAcceptor {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+            .chain_err(|| "failed to create SSL acceptor")?;
+        builder
+            .set_certificate_chain_file(cert_path)
+            .chain_err(|| format!("failed to load SSL certificate from {:?}", cert_path))?;
+        builder
+            .set_private_key_file(key_path, SslFiletype::PEM)
+            .chain_err(|| format!("failed to load SSL private key from {:?}", key_path))?;
+        builder
+            .check_private_key()
+            .chain_err(|| "SSL private key does not match the certificate")?;
+        Ok(TlsAcceptor {
+            acceptor: builder.build(),
+        })
+    }
+
+    /// Starts the server-side handshake (which is completed by reading from the returned stream).
+    pub fn accept(&self, stream: TcpStream) -> Result<TlsStream> {
+        let state = match self.acceptor.accept(stream) {
+            Ok(session) => State::Established(session),
+            Err(HandshakeError::WouldBlock(handshake)) => State::Handshake(handshake),
+            Err(e) => bail!("SSL handshake failed: {}", e),
+        };
+        Ok(TlsStream { state: Some(state) })
+    }
+}
+
+enum State {
+    Handshake(MidHandshakeSslStream<TcpStream>),
+    Established(SslStream<TcpStream>),
+}
+
+/// A non-blocking TLS session, whose handshake is driven by reading and writing it.
+pub struct TlsStream {
+    state: Option<State>, // None after a failed handshake
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "SSL handshake in progress")
+}
+
+impl TlsStream {
+    fn session(&mut self) -> io::Result<&mut SslStream<TcpStream>> {
+        let state = match self.state.take() {
+            Some(State::Handshake(handshake)) => match handshake.handshake() {
+                Ok(session) => State::Established(session),
+                Err(HandshakeError::WouldBlock(handshake)) => {
+                    self.state = Some(State::Handshake(handshake));
+                    return Err(would_block());
+                }
+                Err(e) => {
+                    let msg = format!("SSL handshake failed: {}", e);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            },
+            Some(state) => state,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "SSL handshake failed")),
+        };
+        self.state = Some(state);
+        match self.state {
+            Some(State::Established(ref mut session)) => Ok(session),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.session()?.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session()?.flush()
+    }
+}
@@ -1,6 +1,7 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::network::serialize::BitcoinHash;
 use bitcoin::util::hash::Sha256dHash;
+use std::cmp;
 use std::collections::HashMap;
 use std::fmt;
 use std::iter::FromIterator;
@@ -27,6 +28,37 @@ pub fn full_hash(hash: &[u8]) -> FullHash {
     array_ref![hash, 0, HASH_LEN].clone()
 }
 
+fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
+    let data = [&left[..], &right[..]].concat();
+    Sha256dHash::from_data(&data)
+}
+
+/// Returns the merkle branch of `hashes[index]`, and the merkle root of `hashes`.
+pub fn create_merkle_branch_and_root(
+    mut hashes: Vec<Sha256dHash>,
+    mut index: usize,
+) -> (Vec<Sha256dHash>, Sha256dHash) {
+    let mut merkle = vec![];
+    while hashes.len() > 1 {
+        if hashes.len() % 2 != 0 {
+            let last = hashes.last().unwrap().clone();
+            hashes.push(last);
+        }
+        index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        merkle.push(hashes[index]);
+        index = index / 2;
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merklize(pair[0], pair[1]))
+            .collect()
+    }
+    (merkle, hashes[0])
+}
+
+// Header merkle roots are cached for complete chunks of 2^HEADER_CHUNK_LEVEL headers.
+const HEADER_CHUNK_LEVEL: usize = 11;
+const HEADER_CHUNK_SIZE: usize = 1 << HEADER_CHUNK_LEVEL;
+
 #[derive(Eq, PartialEq, Clone)]
 pub struct HeaderEntry {
     height: usize,
@@ -63,10 +95,12 @@ impl fmt::Debug for HeaderEntry {
     }
 }
 
+#[derive(Clone)]
 pub struct HeaderList {
     headers: Vec<HeaderEntry>,
     heights: HashMap<Sha256dHash, usize>,
     tip: Sha256dHash,
+    chunk_roots: Vec<Sha256dHash>, // merkle roots of complete header chunks
 }
 
 impl HeaderList {
@@ -75,6 +109,7 @@ impl HeaderList {
             headers: vec![],
             heights: HashMap::new(),
             tip: Sha256dHash::default(),
+            chunk_roots: vec![],
         }
     }
 
@@ -152,6 +187,76 @@ impl HeaderList {
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
+        self.update_chunk_roots(new_height);
+    }
+
+    pub fn truncate(&mut self, height: usize) {
+        // keep [0..height) entries (e.g. after disconnecting stale blocks)
+        self.headers.truncate(height);
+        self.tip = self
+            .headers
+            .last()
+            .map(|h| *h.hash())
+            .unwrap_or(Sha256dHash::default());
+        self.update_chunk_roots(height);
+    }
+
+    // Headers at [height..) may have been replaced (e.g. after a reorg).
+    fn update_chunk_roots(&mut self, height: usize) {
+        self.chunk_roots.truncate(height / HEADER_CHUNK_SIZE);
+        let chunks = self.headers.len() / HEADER_CHUNK_SIZE;
+        while self.chunk_roots.len() < chunks {
+            let start = self.chunk_roots.len() * HEADER_CHUNK_SIZE;
+            let root = chunk_root(&self.headers[start..start + HEADER_CHUNK_SIZE]);
+            self.chunk_roots.push(root);
+        }
+    }
+
+    /// Returns the merkle branch of the header at `height`, and the merkle root
+    /// of all the headers up to `cp_height` (inclusive).
+    pub fn header_proof(
+        &self,
+        height: usize,
+        cp_height: usize,
+    ) -> Option<(Vec<Sha256dHash>, Sha256dHash)> {
+        if height > cp_height || cp_height >= self.headers.len() {
+            return None;
+        }
+        let hashes = |entries: &[HeaderEntry]| -> Vec<Sha256dHash> {
+            entries.iter().map(|h| *h.hash()).collect()
+        };
+        let count = cp_height + 1;
+        if count <= HEADER_CHUNK_SIZE {
+            return Some(create_merkle_branch_and_root(
+                hashes(&self.headers[..count]),
+                height,
+            ));
+        }
+        // The tree is split into chunk subtrees, whose roots are the leaves of the upper tree.
+        let chunk_index = height / HEADER_CHUNK_SIZE;
+        let start = chunk_index * HEADER_CHUNK_SIZE;
+        let end = cmp::min(start + HEADER_CHUNK_SIZE, count);
+        let (mut branch, mut root) =
+            create_merkle_branch_and_root(hashes(&self.headers[start..end]), height - start);
+        // A partial chunk is padded by duplicating its last node at each level.
+        while branch.len() < HEADER_CHUNK_LEVEL {
+            branch.push(root);
+            root = merklize(root, root);
+        }
+        let mut roots: Vec<Sha256dHash> = self.chunk_roots[..chunk_index].to_vec();
+        roots.push(root);
+        let last_chunk = cp_height / HEADER_CHUNK_SIZE;
+        for index in (chunk_index + 1)..(last_chunk + 1) {
+            roots.push(if index < last_chunk {
+                self.chunk_roots[index]
+            } else {
+                let start = index * HEADER_CHUNK_SIZE;
+                partial_chunk_root(&self.headers[start..count])
+            });
+        }
+        let (upper_branch, root) = create_merkle_branch_and_root(roots, chunk_index);
+        branch.extend(upper_branch);
+        Some((branch, root))
     }
 
     pub fn header_by_blockhash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
@@ -195,6 +300,22 @@ impl HeaderList {
     }
 }
 
+fn chunk_root(entries: &[HeaderEntry]) -> Sha256dHash {
+    let hashes = entries.iter().map(|h| *h.hash()).collect();
+    create_merkle_branch_and_root(hashes, 0).1
+}
+
+fn partial_chunk_root(entries: &[HeaderEntry]) -> Sha256dHash {
+    let (branch, mut root) = create_merkle_branch_and_root(
+        entries.iter().map(|h| *h.hash()).collect(),
+        entries.len() - 1,
+    );
+    for _ in branch.len()..HEADER_CHUNK_LEVEL {
+        root = merklize(root, root);
+    }
+    root
+}
+
 pub struct SyncChannel<T> {
     tx: SyncSender<T>,
     rx: Receiver<T>,
@@ -0,0 +1,175 @@
+use base64;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use errors::*;
+
+// See https://tools.ietf.org/html/rfc6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\r\n";
+
+pub enum Incoming {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Parses the client's opening handshake (if fully received) from the beginning of `buf`,
+/// returning its length and the server's response.
+pub fn accept(buf: &[u8]) -> Result<Option<(usize, Vec<u8>)>> {
+    let len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() > MAX_HANDSHAKE_SIZE => bail!("WebSocket handshake is too large"),
+        None => return Ok(None),
+    };
+    let request = String::from_utf8(buf[..len].to_vec())
+        .chain_err(|| "invalid UTF8 in WebSocket handshake")?;
+    let key = parse_handshake_key(&request)?;
+    let mut sha1 = Sha1::new();
+    sha1.input_str(&key);
+    sha1.input_str(ACCEPT_GUID);
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        base64::encode(&digest)
+    );
+    Ok(Some((len, response.into_bytes())))
+}
+
+fn parse_handshake_key(request: &str) -> Result<String> {
+    let mut lines = request.lines();
+    let request_line = lines.next().unwrap_or("");
+    if !request_line.starts_with("GET ") {
+        bail!("invalid WebSocket handshake: {:?}", request_line);
+    }
+    let mut upgrade = false;
+    let mut key = None;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap().trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        match name.as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value.to_owned()),
+            _ => (),
+        }
+    }
+    if !upgrade {
+        bail!("missing WebSocket upgrade header");
+    }
+    key.chain_err(|| "missing Sec-WebSocket-Key header")
+}
+
+/// Reassembles (possibly fragmented) client messages from received frames.
+pub struct Decoder {
+    message: Vec<u8>, // payload of the frames received so far
+    max_size: usize,  // of a single message
+}
+
+impl Decoder {
+    pub fn new(max_size: usize) -> Decoder {
+        Decoder {
+            message: vec![],
+            max_size,
+        }
+    }
+
+    /// Consumes the complete frames at the beginning of `buf`, until a message is decoded.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Incoming>> {
+        loop {
+            if buf.len() < 2 {
+                return Ok(None);
+            }
+            let fin = buf[0] & 0x80 != 0;
+            let opcode = buf[0] & 0x0F;
+            if buf[1] & 0x80 == 0 {
+                bail!("unmasked WebSocket frame");
+            }
+            let (len, offset) = match buf[1] & 0x7F {
+                126 => (read_be_uint(&buf[2..], 2), 4),
+                127 => (read_be_uint(&buf[2..], 8), 10),
+                len => (Some(u64::from(len)), 2),
+            };
+            let len = match len {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            if len > (self.max_size - self.message.len()) as u64 {
+                bail!(ErrorKind::LimitExceeded(
+                    "request_size",
+                    format!("request is larger than {} bytes", self.max_size)
+                ));
+            }
+            let (mask_offset, payload_offset) = (offset, offset + 4);
+            let end = payload_offset + len as usize;
+            if buf.len() < end {
+                return Ok(None);
+            }
+            let mut payload = buf[payload_offset..end].to_vec();
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= buf[mask_offset + i % 4];
+            }
+            buf.drain(..end);
+            match opcode {
+                OPCODE_CLOSE => return Ok(Some(Incoming::Close)),
+                OPCODE_PING => return Ok(Some(Incoming::Ping(payload))),
+                OPCODE_PONG => continue,
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => self.message.extend(payload),
+                _ => bail!("unsupported WebSocket opcode {}", opcode),
+            }
+            if fin {
+                let message = self.message.split_off(0);
+                let text = String::from_utf8(message).chain_err(|| "invalid UTF8 message")?;
+                return Ok(Some(Incoming::Text(text)));
+            }
+        }
+    }
+}
+
+fn read_be_uint(buf: &[u8], size: usize) -> Option<u64> {
+    if buf.len() < size {
+        return None;
+    }
+    Some(
+        buf[..size]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b)),
+    )
+}
+
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode]; // server frames are final and unmasked
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.extend(&[(len >> 8) as u8, len as u8]);
+    } else {
+        frame.push(127);
+        frame.extend((0..8).rev().map(|i| ((len as u64) >> (8 * i)) as u8));
+    }
+    frame.extend(payload);
+    frame
+}
+
+pub fn encode_text(text: &str) -> Vec<u8> {
+    encode_frame(OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn encode_pong(payload: &[u8]) -> Vec<u8> {
+    encode_frame(OPCODE_PONG, payload)
+}